@@ -1,43 +1,407 @@
-use std::ops::Deref;
-use std::path::Path;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::FusedIterator;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Debug)]
+pub mod restore;
+
+pub use restore::{
+    restore_archive, restore_archives, FailedEntry, RemoteTarget, RestoreError, RestoreOptions,
+    RestoreStats, UploadedFile,
+};
+
+/// The longest path `SimplePath::try_new` accepts, Linux's `PATH_MAX`.
+pub const MAX_PATH_LEN: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathError {
+    Absolute(String),
+    Traversal(String),
+    ControlChar(String),
+    TooLong(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Absolute(p) => write!(f, "path is absolute: {}", p),
+            PathError::Traversal(p) => write!(f, "path escapes its root: {}", p),
+            PathError::ControlChar(p) => {
+                write!(f, "path contains a control character: {}", p.escape_debug())
+            }
+            PathError::TooLong(p) => write!(
+                f,
+                "path is longer than {} bytes: {}...",
+                MAX_PATH_LEN,
+                p.chars().take(64).collect::<String>()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct SimplePath {
     buf: String,
 }
 
 impl SimplePath {
-    pub fn split<S: AsRef<str>>(r: &S) -> impl Iterator<Item = &str> {
+    pub fn split<S: AsRef<str>>(r: &S) -> impl DoubleEndedIterator<Item = &str> {
         r.as_ref().split(&['/', '\\']).filter(|&p| !p.is_empty())
     }
 
+    /// Whether `r` ends in a separator, the mark of a directory that [`new`](Self::new) drops.
+    /// It isn't kept in the path itself so `a/` and `a` still compare and hash the same.
+    pub fn ends_with_separator<S: AsRef<str>>(r: &S) -> bool {
+        r.as_ref().ends_with(['/', '\\'])
+    }
+
     pub fn new<S: AsRef<str>>(r: S) -> Self {
-        let mut rooted = if r.as_ref().starts_with('/') { "/" } else { "" }.to_owned();
-        PathJoiner::new(Self::split(&r)).for_each(|p| rooted += p);
-        Self { buf: rooted }
+        Self::from_parts(r.as_ref().starts_with('/'), Self::split(&r))
+    }
+
+    /// Like `new`, but fails on names no server would accept, a control character or more than
+    /// `MAX_PATH_LEN` bytes. This can't be `TryFrom<&str>`, the `From<&str>` impl already
+    /// provides an infallible one.
+    pub fn try_new<S: AsRef<str>>(r: S) -> Result<Self, PathError> {
+        let r = r.as_ref();
+        if r.contains(char::is_control) {
+            Err(PathError::ControlChar(r.to_owned()))
+        } else if r.len() > MAX_PATH_LEN {
+            Err(PathError::TooLong(r.to_owned()))
+        } else {
+            Ok(Self::new(r))
+        }
+    }
+
+    pub fn new_safe<S: AsRef<str>>(r: S) -> Result<Self, PathError> {
+        let r = r.as_ref();
+        if r.starts_with(['/', '\\']) {
+            return Err(PathError::Absolute(r.to_owned()));
+        }
+        let path = Self::new(r).normalize();
+        if path.starts_with("..") {
+            Err(PathError::Traversal(r.to_owned()))
+        } else {
+            Ok(path)
+        }
+    }
+
+    fn from_parts<'a>(rooted: bool, parts: impl Iterator<Item = &'a str>) -> Self {
+        let joiner = if rooted {
+            PathJoiner::rooted(parts)
+        } else {
+            PathJoiner::new(parts)
+        };
+        Self {
+            buf: String::from_iter(joiner),
+        }
     }
 
-    pub fn as_str(self: &Self) -> &str {
+    pub fn as_str(&self) -> &str {
         self.as_ref()
     }
 
-    pub fn join<S: AsRef<str>>(self: &Self, r: S) -> Self {
+    pub fn is_absolute(&self) -> bool {
+        self.buf.starts_with('/')
+    }
+
+    pub fn is_relative(&self) -> bool {
+        !self.is_absolute()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.buf == "/"
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn join<S: AsRef<str>>(&self, r: S) -> Self {
         let other = SimplePath::new(r);
-        if other.as_str().starts_with('/') {
+        if other.is_absolute() {
             other
         } else {
-            let path = String::from_iter(PathJoiner::new(
-                [self.as_str(), other.as_str()]
-                    .into_iter()
-                    .filter(|&p| !p.is_empty()),
-            ));
-            Self { buf: path }
+            let parts = Self::split(&self.buf).chain(Self::split(&other.buf));
+            Self::from_parts(self.is_absolute(), parts)
         }
     }
 
-    pub fn ancestors(self: &Self) -> impl Iterator<Item = &str> {
+    /// Like [`join`](Self::join), but resolves `.` and `..` in the result the way
+    /// [`normalize`](Self::normalize) does, so a `..` in `r` eats into `self`.
+    pub fn join_normalized<S: AsRef<str>>(&self, r: S) -> Self {
+        self.join(r).normalize()
+    }
+
+    pub fn normalize(&self) -> Self {
+        let rooted = self.is_absolute();
+        Self::from_parts(rooted, Self::resolve_dots(rooted, Self::split(&self.buf)))
+    }
+
+    /// Drops `.` and lets each `..` cancel the part before it, or the root when there is none.
+    fn resolve_dots<'a>(
+        rooted: bool,
+        parts: impl Iterator<Item = &'a str>,
+    ) -> impl Iterator<Item = &'a str> {
+        let mut resolved: Vec<&str> = Vec::new();
+        for part in parts {
+            match part {
+                "." => (),
+                ".." => match resolved.last() {
+                    Some(&p) if p != ".." => {
+                        resolved.pop();
+                    }
+                    _ if rooted => (),
+                    _ => resolved.push(".."),
+                },
+                p => resolved.push(p),
+            }
+        }
+        resolved.into_iter()
+    }
+
+    /// Resolves `self` under `base`, treating an absolute `self` as relative to it. A `..` that
+    /// would climb above `base` is dropped rather than followed, so the result always starts
+    /// with `base`.
+    pub fn canonicalize_against(&self, base: &SimplePath) -> Self {
+        let mut parts: Vec<&str> = Vec::new();
+        for part in Self::split(&self.buf) {
+            match part {
+                "." => (),
+                ".." => {
+                    parts.pop();
+                }
+                p => parts.push(p),
+            }
+        }
+        let base = base.normalize();
+        Self::from_parts(base.is_absolute(), Self::split(&base.buf).chain(parts))
+    }
+
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &str> {
+        let root = if self.is_absolute() { Some("/") } else { None };
+        root.into_iter().chain(Self::split(&self.buf))
+    }
+
+    pub fn depth(&self) -> usize {
+        Self::split(&self.buf).count()
+    }
+
+    pub fn strip_components(&self, n: usize) -> Option<Self> {
+        if n == 0 {
+            return Some(self.clone());
+        }
+        let mut parts = Self::split(&self.buf).skip(n).peekable();
+        parts.peek()?;
+        Some(Self::from_parts(false, parts))
+    }
+
+    pub fn strip_prefix<S: AsRef<str>>(&self, base: S) -> Option<Self> {
+        let base = SimplePath::new(base);
+        if base.is_empty() {
+            return Some(self.clone());
+        }
+        let mut parts = self.components();
+        for prefix in base.components() {
+            if parts.next() != Some(prefix) {
+                return None;
+            }
+        }
+        Some(Self {
+            buf: String::from_iter(PathJoiner::new(parts)),
+        })
+    }
+
+    pub fn starts_with<S: AsRef<str>>(&self, prefix: S) -> bool {
+        let prefix = SimplePath::new(prefix);
+        let mut parts = self.components();
+        let matched = prefix.components().all(|p| parts.next() == Some(p));
+        matched
+    }
+
+    pub fn ends_with<S: AsRef<str>>(&self, suffix: S) -> bool {
+        let suffix = SimplePath::new(suffix);
+        let mut parts = self.components().rev();
+        let matched = suffix.components().rev().all(|p| parts.next() == Some(p));
+        matched
+    }
+
+    pub fn common_prefix(&self, other: &SimplePath) -> Self {
+        if self.is_absolute() != other.is_absolute() {
+            return Self::default();
+        }
+        let parts = Self::split(&self.buf)
+            .zip(Self::split(&other.buf))
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a);
+        Self::from_parts(self.is_absolute(), parts)
+    }
+
+    pub fn relative_to(&self, base: &SimplePath) -> Option<Self> {
+        if self.is_absolute() != base.is_absolute() {
+            return None;
+        }
+        let (path, base) = (self.normalize(), base.normalize());
+        let common = path.common_prefix(&base).depth();
+        let ups: Vec<_> = Self::split(&base.buf).skip(common).collect();
+        if ups.contains(&"..") {
+            return None;
+        }
+        let parts = ups
+            .into_iter()
+            .map(|_| "..")
+            .chain(Self::split(&path.buf).skip(common));
+        let relative = Self::from_parts(false, parts);
+        if relative.is_empty() {
+            Some(Self::new("."))
+        } else {
+            Some(relative)
+        }
+    }
+
+    /// Renders the path with `\` separators for Windows servers. The leading `/` is dropped, so
+    /// the `/C:/Users` form Windows OpenSSH uses for drive paths becomes `C:\Users`.
+    pub fn to_windows_string(&self) -> String {
+        String::from_iter(PathJoiner::new(Self::split(&self.buf)).map(|p| match p {
+            "/" => "\\",
+            p => p,
+        }))
+    }
+
+    pub fn ancestors(&self) -> impl FusedIterator<Item = &str> {
         PathAncestors::new(self.as_str())
     }
+
+    pub fn join_all<I, S>(&self, segments: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut path = self.clone();
+        segments.into_iter().for_each(|s| path.push(s));
+        path
+    }
+
+    pub fn push<S: AsRef<str>>(&mut self, r: S) {
+        if r.as_ref().starts_with('/') {
+            *self = SimplePath::new(r);
+            return;
+        }
+        for part in Self::split(&r) {
+            if !self.buf.is_empty() && !self.buf.ends_with('/') {
+                self.buf.push('/');
+            }
+            self.buf.push_str(part);
+        }
+    }
+
+    pub fn pop(&mut self) -> bool {
+        if self.is_empty() || self.is_root() {
+            return false;
+        }
+        match self.buf.rfind('/') {
+            Some(0) => self.buf.truncate(1),
+            Some(x) => self.buf.truncate(x),
+            None => self.buf.clear(),
+        }
+        true
+    }
+
+    /// The directories that have to exist before the path itself can be created, root first,
+    /// which is [`ancestors_rev`](Self::ancestors_rev) without the path at the end.
+    pub fn dir_ancestors(&self) -> impl DoubleEndedIterator<Item = &str> {
+        let mut dirs = self.ancestors_rev();
+        dirs.next_back();
+        dirs
+    }
+
+    pub fn ancestors_rev(&self) -> impl DoubleEndedIterator<Item = &str> {
+        let buf = self.as_str();
+        let root = if self.is_absolute() { Some(1) } else { None };
+        let dirs = buf.match_indices('/').map(|(i, _)| i).filter(|&i| i > 0);
+        let leaf = if buf.len() > root.unwrap_or(0) {
+            Some(buf.len())
+        } else {
+            None
+        };
+        root.into_iter()
+            .chain(dirs)
+            .chain(leaf)
+            .map(move |i| &buf[0..i])
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        let mut parent = self.clone();
+        parent.pop().then_some(parent)
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        match self.buf.rsplit('/').next() {
+            Some("") | Some("..") | None => None,
+            name => name,
+        }
+    }
+
+    pub fn with_file_name<S: AsRef<str>>(&self, name: S) -> Self {
+        let mut path = match self.file_name() {
+            Some(_) => self.parent().unwrap_or_default(),
+            None if self.depth() == 0 => Self::default(),
+            None => self.clone(),
+        };
+        path.push(name);
+        path
+    }
+
+    fn split_file_name(&self) -> Option<(&str, Option<&str>)> {
+        let name = self.file_name()?;
+        match name.rfind('.') {
+            Some(0) | None => Some((name, None)),
+            Some(x) => Some((&name[0..x], Some(&name[x + 1..]))),
+        }
+    }
+
+    pub fn file_stem(&self) -> Option<&str> {
+        self.split_file_name().map(|(stem, _)| stem)
+    }
+
+    pub fn extension(&self) -> Option<&str> {
+        self.split_file_name().and_then(|(_, ext)| ext)
+    }
+
+    pub fn with_extension<S: AsRef<str>>(&self, ext: S) -> Self {
+        let mut buf = SimplePathBuf::from(self.clone());
+        buf.set_extension(ext);
+        buf.into_simple_path()
+    }
+
+    /// Adds `ext` after any extension already there, so `backup.tar` becomes `backup.tar.gz`.
+    /// Paths without a file name and empty extensions are left as they are.
+    pub fn push_extension<S: AsRef<str>>(&self, ext: S) -> Self {
+        let ext = ext.as_ref();
+        if self.file_name().is_none() || ext.is_empty() {
+            return self.clone();
+        }
+        Self {
+            buf: format!("{}.{}", self.buf, ext),
+        }
+    }
+}
+
+/// The text of `SimplePath::new(s).normalize()`, for logging or comparing a path without
+/// keeping a [`SimplePath`] around.
+pub fn normalize_str<S: AsRef<str>>(s: S) -> String {
+    let rooted = s.as_ref().starts_with('/');
+    let parts = SimplePath::resolve_dots(rooted, SimplePath::split(&s));
+    let joiner = if rooted {
+        PathJoiner::rooted(parts)
+    } else {
+        PathJoiner::new(parts)
+    };
+    String::from_iter(joiner)
 }
 
 impl AsRef<str> for SimplePath {
@@ -46,12 +410,93 @@ impl AsRef<str> for SimplePath {
     }
 }
 
+/// A borrowed [`SimplePath`], so collections of paths can be queried without allocating, as
+/// `mkdir_r` does for every ancestor of every file. `Borrow<str>` can't do this: a borrowed form
+/// has to compare like the owned one, and paths compare component by component, so `/a/b` sorts
+/// before `/a-b` while the strings sort the other way round. An ordered collection would then
+/// miss entries.
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub(crate) struct PathStr(str);
+
+impl PathStr {
+    /// `s` must already be in the form [`SimplePath::new`] produces, which a path's ancestors
+    /// are. Anything else compares unequal to the path it names.
+    pub(crate) fn new(s: &str) -> &PathStr {
+        debug_assert_eq!(SimplePath::new(s).as_str(), s, "not a normalized path");
+        Self::new_unchecked(s)
+    }
+
+    /// For the string of a [`SimplePath`], which is always normalized.
+    fn new_unchecked(s: &str) -> &PathStr {
+        // SAFETY: `PathStr` is a `repr(transparent)` wrapper around `str`
+        unsafe { &*(s as *const str as *const PathStr) }
+    }
+
+    fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+}
+
+impl Ord for PathStr {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .is_absolute()
+            .cmp(&self.is_absolute())
+            .then_with(|| SimplePath::split(&&self.0).cmp(SimplePath::split(&&other.0)))
+    }
+}
+
+impl PartialOrd for PathStr {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Borrow<PathStr> for SimplePath {
+    fn borrow(&self) -> &PathStr {
+        PathStr::new_unchecked(&self.buf)
+    }
+}
+
 impl AsRef<Path> for SimplePath {
     fn as_ref(&self) -> &Path {
         Path::new(self.buf.as_str())
     }
 }
 
+impl Ord for SimplePath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        PathStr::new_unchecked(&self.buf).cmp(PathStr::new_unchecked(&other.buf))
+    }
+}
+
+impl PartialOrd for SimplePath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for SimplePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for SimplePath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for SimplePath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SimplePath::new)
+    }
+}
+
 impl Deref for SimplePath {
     type Target = Path;
 
@@ -60,21 +505,130 @@ impl Deref for SimplePath {
     }
 }
 
-impl Into<SimplePath> for &str {
-    fn into(self) -> SimplePath {
-        SimplePath::new(self)
+impl From<&str> for SimplePath {
+    fn from(val: &str) -> Self {
+        SimplePath::new(val)
+    }
+}
+
+impl From<&Path> for SimplePath {
+    fn from(val: &Path) -> Self {
+        SimplePath::new(val.to_string_lossy())
+    }
+}
+
+impl From<PathBuf> for SimplePath {
+    fn from(val: PathBuf) -> Self {
+        SimplePath::from(val.as_path())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SimplePathBuf {
+    inner: SimplePath,
+}
+
+impl SimplePathBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_path(&self) -> &SimplePath {
+        &self.inner
+    }
+
+    pub fn into_simple_path(self) -> SimplePath {
+        self.inner
+    }
+
+    pub fn set_extension<S: AsRef<str>>(&mut self, ext: S) -> bool {
+        let (name, stem) = match (self.inner.file_name(), self.inner.file_stem()) {
+            (Some(name), Some(stem)) => (name.len(), stem.len()),
+            _ => return false,
+        };
+        let buf = &mut self.inner.buf;
+        buf.truncate(buf.len() - name + stem);
+        let ext = ext.as_ref();
+        if !ext.is_empty() {
+            buf.push('.');
+            buf.push_str(ext);
+        }
+        true
+    }
+}
+
+impl From<SimplePath> for SimplePathBuf {
+    fn from(inner: SimplePath) -> Self {
+        Self { inner }
+    }
+}
+
+impl From<SimplePathBuf> for SimplePath {
+    fn from(buf: SimplePathBuf) -> Self {
+        buf.into_simple_path()
+    }
+}
+
+/// Appends each item as one or more components. Unlike `push`, an item with a leading `/`
+/// doesn't reset the path to the root, so mapping over `components()` can't lose the prefix.
+impl<S: AsRef<str>> Extend<S> for SimplePathBuf {
+    fn extend<I: IntoIterator<Item = S>>(&mut self, iter: I) {
+        for part in iter {
+            self.push(part.as_ref().trim_start_matches(['/', '\\']));
+        }
+    }
+}
+
+/// The path is absolute if the first item starts with a `/`, like the `"/"` `components()`
+/// yields for an absolute path, and relative otherwise. Later items extend it as `Extend` does.
+impl<S: AsRef<str>> FromIterator<S> for SimplePath {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        let mut iter = iter.into_iter().peekable();
+        let mut buf = match iter.peek() {
+            Some(first) if first.as_ref().starts_with('/') => SimplePathBuf::from(Self::new("/")),
+            _ => SimplePathBuf::new(),
+        };
+        buf.extend(iter);
+        buf.into_simple_path()
+    }
+}
+
+impl Deref for SimplePathBuf {
+    type Target = SimplePath;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for SimplePathBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
     }
 }
 
 pub struct PathJoiner<'a, I: Iterator<Item = &'a str>> {
     inner: I,
     next: Option<&'a str>,
+    root: bool,
 }
 
 impl<'a, I: Iterator<Item = &'a str>> PathJoiner<'a, I> {
     pub fn new(mut inner: I) -> Self {
         let next = inner.next();
-        Self { inner, next }
+        Self {
+            inner,
+            next,
+            root: false,
+        }
+    }
+
+    /// Like `new`, but starts with a `/` so the joined parts form an absolute path.
+    pub fn rooted(inner: I) -> Self {
+        Self {
+            root: true,
+            ..Self::new(inner)
+        }
     }
 }
 
@@ -82,6 +636,10 @@ impl<'a, I: Iterator<Item = &'a str>> Iterator for PathJoiner<'a, I> {
     type Item = &'a str;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.root {
+            self.root = false;
+            return Some("/");
+        }
         match self.next {
             Some(part) => {
                 self.next = None;
@@ -102,6 +660,7 @@ struct PathAncestors<'a> {
     inner: &'a str,
     next: Option<usize>,
     done: bool,
+    remaining: usize,
 }
 
 impl<'a> PathAncestors<'a> {
@@ -112,10 +671,13 @@ impl<'a> PathAncestors<'a> {
         } else {
             trimmed_pth
         };
+        // one per component, plus the root itself
+        let remaining = SimplePath::split(&pth).count() + usize::from(pth.starts_with('/'));
         Self {
             inner: pth,
             next: None,
-            done: false,
+            done: pth.is_empty(),
+            remaining,
         }
     }
 }
@@ -126,7 +688,7 @@ impl<'a> Iterator for PathAncestors<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         match self.next {
             Some(x) if x > 0 => {
-                self.inner = &self.inner[0..x].trim_end_matches('/');
+                self.inner = self.inner[0..x].trim_end_matches('/');
                 self.next = self.inner.rfind('/');
             }
             Some(0) if self.inner.len() > 1 => {
@@ -139,13 +701,20 @@ impl<'a> Iterator for PathAncestors<'a> {
             }
             _ => return None,
         }
-        if let None = self.next {
+        if self.next.is_none() {
             self.done = true;
         }
+        self.remaining -= 1;
         Some(self.inner)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
 }
 
+impl FusedIterator for PathAncestors<'_> {}
+
 #[cfg(test)]
 mod test {
     use crate::*;
@@ -164,6 +733,16 @@ mod test {
         assert_eq!(path_joiner.next(), None);
     }
 
+    #[test]
+    fn test_path_joiner_rooted() {
+        let path_joiner = PathJoiner::rooted(SimplePath::split(&"var//run"));
+        assert_eq!(path_joiner.collect::<Vec<_>>(), ["/", "var", "/", "run"]);
+
+        let mut path_joiner = PathJoiner::rooted("".split("/").take(0));
+        assert_eq!(path_joiner.next(), Some("/"));
+        assert_eq!(path_joiner.next(), None);
+    }
+
     #[test]
     fn test_empty_iter() {
         let iter = "".split("/").take(0);
@@ -172,6 +751,78 @@ mod test {
         assert_eq!(path_joiner.next(), None);
     }
 
+    #[test]
+    fn test_is_absolute() {
+        for p in ["/", "/var/run", "//var\\run/"] {
+            assert!(SimplePath::new(p).is_absolute());
+            assert!(!SimplePath::new(p).is_relative());
+        }
+        for p in ["", "var", "var/run", "\\var"] {
+            assert!(SimplePath::new(p).is_relative());
+            assert!(!SimplePath::new(p).is_absolute());
+        }
+    }
+
+    #[test]
+    fn test_is_root_empty() {
+        for (p, root, empty) in [
+            ("/", true, false),
+            ("////", true, false),
+            ("\\", false, true),
+            ("", false, true),
+            ("/var", false, false),
+            ("var", false, false),
+        ] {
+            assert_eq!(SimplePath::new(p).is_root(), root, "{}", p);
+            assert_eq!(SimplePath::new(p).is_empty(), empty, "{}", p);
+        }
+        assert!(SimplePath::new("/a/..").normalize().is_root());
+        assert!(SimplePath::new("a/..").normalize().is_empty());
+    }
+
+    #[test]
+    fn test_new_safe() {
+        let p = |s: &str| SimplePath::new_safe(s).map(|p| p.as_str().to_owned());
+        assert_eq!(p("etc/passwd").as_deref(), Ok("etc/passwd"));
+        assert_eq!(p("./a/../b//c").as_deref(), Ok("b/c"));
+        assert_eq!(p("a/..").as_deref(), Ok(""));
+        assert_eq!(
+            p("/etc/shadow"),
+            Err(PathError::Absolute("/etc/shadow".to_owned()))
+        );
+        assert_eq!(p("\\etc"), Err(PathError::Absolute("\\etc".to_owned())));
+        assert_eq!(
+            p("../../etc/passwd"),
+            Err(PathError::Traversal("../../etc/passwd".to_owned()))
+        );
+        assert_eq!(
+            p("a/../../b"),
+            Err(PathError::Traversal("a/../../b".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_try_new() {
+        let path = SimplePath::try_new("/var//run/").unwrap();
+        assert_eq!(path.as_str(), "/var/run");
+        assert_eq!(
+            SimplePath::try_new("caf\u{e9}").unwrap().as_str(),
+            "caf\u{e9}"
+        );
+        assert_eq!(
+            SimplePath::try_new("etc/pass\0wd"),
+            Err(PathError::ControlChar("etc/pass\0wd".to_owned()))
+        );
+        assert!(SimplePath::try_new("a\nb").is_err());
+        assert!(SimplePath::try_new("tab\tbed").is_err());
+
+        let long = "a/".repeat(MAX_PATH_LEN / 2);
+        assert!(SimplePath::try_new(&long[..MAX_PATH_LEN]).is_ok());
+        let err = SimplePath::try_new(format!("{}a", long)).unwrap_err();
+        assert!(matches!(err, PathError::TooLong(_)));
+        assert!(err.to_string().len() < 200);
+    }
+
     #[test]
     fn test_join() {
         let p1 = SimplePath::new("/var/run/");
@@ -180,6 +831,14 @@ mod test {
         assert_eq!(joined.as_str(), "/var/run/test");
     }
 
+    #[test]
+    fn test_join_root() {
+        assert_eq!(SimplePath::new("/").join("var").as_str(), "/var");
+        assert_eq!(SimplePath::new("/").join("").as_str(), "/");
+        assert_eq!(SimplePath::new("").join("var/").as_str(), "var");
+        assert_eq!(SimplePath::new("").join("").as_str(), "");
+    }
+
     #[test]
     fn test_join_second_rooted() {
         let p1 = SimplePath::new("/var/run/");
@@ -198,6 +857,568 @@ mod test {
         assert_eq!(joined2.as_str(), "/var/run");
     }
 
+    #[test]
+    fn test_join_normalized() {
+        let base = SimplePath::new("/srv/restore");
+        assert_eq!(base.join("../x").as_str(), "/srv/restore/../x");
+        assert_eq!(base.join_normalized("../x").as_str(), "/srv/x");
+        assert_eq!(base.join_normalized("./a/../b/").as_str(), "/srv/restore/b");
+        assert_eq!(base.join_normalized("/etc/../tmp").as_str(), "/tmp");
+        assert_eq!(base.join_normalized("../../../../x").as_str(), "/x");
+        assert_eq!(SimplePath::new("/").join_normalized("..").as_str(), "/");
+
+        let base = SimplePath::new("backups/today");
+        assert_eq!(base.join_normalized("../x").as_str(), "backups/x");
+        assert_eq!(base.join_normalized("../../../x").as_str(), "../x");
+        assert_eq!(
+            base.join_normalized("..").join_normalized("..").as_str(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_join_all() {
+        let base = SimplePath::new("/srv");
+        let joined = base.join_all(["backups", "host/", "2022-03-01"]);
+        assert_eq!(joined.as_str(), "/srv/backups/host/2022-03-01");
+
+        let joined = base.join_all(["backups", "/var/lib", "host"]);
+        assert_eq!(joined.as_str(), "/var/lib/host");
+
+        let joined = SimplePath::new("").join_all(["", "a", ""]);
+        assert_eq!(joined.as_str(), "a");
+        assert_eq!(base.join_all(Vec::<String>::new()), base);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut path = SimplePath::new("/var");
+        path.push("run/");
+        assert_eq!(path.as_str(), "/var/run");
+        path.push("a\\b");
+        assert_eq!(path.as_str(), "/var/run/a/b");
+        path.push("");
+        assert_eq!(path.as_str(), "/var/run/a/b");
+        path.push("/etc");
+        assert_eq!(path.as_str(), "/etc");
+        assert!(path.pop());
+        assert_eq!(path.as_str(), "/");
+        assert!(!path.pop());
+        assert_eq!(path.as_str(), "/");
+
+        let mut path = SimplePath::new("");
+        path.push("var");
+        path.push("run");
+        assert_eq!(path.as_str(), "var/run");
+        assert!(path.pop());
+        assert!(path.pop());
+        assert_eq!(path.as_str(), "");
+        assert!(!path.pop());
+    }
+
+    #[test]
+    fn test_eq() {
+        use std::collections::HashSet;
+
+        assert_eq!(SimplePath::new("/var//run/"), SimplePath::new("/var/run"));
+        assert_eq!(SimplePath::new("var\\run"), SimplePath::new("var/run"));
+        assert_ne!(SimplePath::new("/var/run"), SimplePath::new("var/run"));
+
+        let mut set = HashSet::new();
+        set.insert(SimplePath::new("/var//run/"));
+        assert!(set.contains(&SimplePath::new("/var/run")));
+    }
+
+    #[test]
+    fn test_borrow() {
+        use std::collections::{BTreeSet, HashMap, HashSet};
+
+        let set = HashSet::from([SimplePath::new("/var//run/"), SimplePath::new("a\\b")]);
+        assert!(set.contains(PathStr::new("/var/run")));
+        assert!(set.contains(PathStr::new("a/b")));
+        assert!(!set.contains(PathStr::new("/var")));
+
+        let mut map = HashMap::new();
+        map.insert(SimplePath::new("/srv/backups"), 1);
+        assert_eq!(map.get(PathStr::new("/srv/backups")), Some(&1));
+
+        // "/a/b" sorts before "/a-b" as a path but after it as a string
+        let set = BTreeSet::from(["/a", "/a/b", "/a/b/c", "/a-b", "/b", "c"].map(SimplePath::new));
+        for pth in ["/a", "/a/b", "/a/b/c", "/a-b", "/b", "c"] {
+            assert!(set.contains(PathStr::new(pth)), "{}", pth);
+        }
+        assert!(!set.contains(PathStr::new("/a/c")));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not a normalized path")]
+    fn test_path_str_unnormalized() {
+        // would silently miss a/b in a collection
+        PathStr::new("a//b");
+    }
+
+    #[test]
+    fn test_ord() {
+        let p = |s: &str| SimplePath::new(s);
+        assert!(p("/a/b") < p("/a/c"));
+        assert!(p("/a/b") < p("/a/b/c"));
+        assert!(p("/a/b/c") < p("/a/c"));
+        assert!(p("/a/b") < p("/a-b"));
+        assert!(p("/") < p("/a"));
+        assert!(p("/z") < p("a"));
+        assert_eq!(p("/a//b/").cmp(&p("/a/b")), Ordering::Equal);
+
+        let mut paths = [p("/a/c"), p("/a/b/c"), p("/a"), p("/a/b"), p("/a-b")];
+        paths.sort();
+        let paths: Vec<_> = paths.iter().map(|p| p.as_str()).collect();
+        assert_eq!(paths, ["/a", "/a/b", "/a/b/c", "/a/c", "/a-b"]);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        for s in ["/var/run", "var/run", "a", "/", "", "../x/./y"] {
+            let path = SimplePath::new(s);
+            assert_eq!(path.components().collect::<SimplePath>(), path, "{}", s);
+        }
+        let upper: SimplePath = SimplePath::new("/srv/data")
+            .components()
+            .map(str::to_uppercase)
+            .collect();
+        assert_eq!(upper.as_str(), "/SRV/DATA");
+        assert_eq!(
+            ["/srv", "a/b", "/c"]
+                .into_iter()
+                .collect::<SimplePath>()
+                .as_str(),
+            "/srv/a/b/c"
+        );
+        assert_eq!(
+            ["srv", "/c"].into_iter().collect::<SimplePath>().as_str(),
+            "srv/c"
+        );
+
+        let mut buf = SimplePathBuf::from(SimplePath::new("/srv"));
+        buf.extend(["restore", "/etc//motd"]);
+        assert_eq!(buf.as_str(), "/srv/restore/etc/motd");
+        let mut buf = SimplePathBuf::new();
+        buf.extend(SimplePath::new("/a/b").components());
+        assert_eq!(buf.as_str(), "a/b");
+    }
+
+    #[test]
+    fn test_components() {
+        let path = SimplePath::new("/var//run/");
+        assert_eq!(path.components().collect::<Vec<_>>(), ["/", "var", "run"]);
+        let path = SimplePath::new("var\\run");
+        assert_eq!(path.components().collect::<Vec<_>>(), ["var", "run"]);
+        let path = SimplePath::new("/");
+        assert_eq!(path.components().collect::<Vec<_>>(), ["/"]);
+        let path = SimplePath::new("");
+        assert_eq!(path.components().next(), None);
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(SimplePath::new("/").depth(), 0);
+        assert_eq!(SimplePath::new("/a").depth(), 1);
+        assert_eq!(SimplePath::new("/a/b/c/").depth(), 3);
+        assert_eq!(SimplePath::new("").depth(), 0);
+        assert_eq!(SimplePath::new("a").depth(), 1);
+        assert_eq!(SimplePath::new("a//b\\c").depth(), 3);
+    }
+
+    #[test]
+    fn test_strip_components() {
+        let strip = |s: &str, n| {
+            SimplePath::new(s)
+                .strip_components(n)
+                .map(|p| p.to_string())
+        };
+        assert_eq!(strip("wrap/a/b", 1).as_deref(), Some("a/b"));
+        assert_eq!(strip("./wrap/a", 2).as_deref(), Some("a"));
+        assert_eq!(strip("/wrap/a", 1).as_deref(), Some("a"));
+        assert_eq!(strip("/wrap/a", 0).as_deref(), Some("/wrap/a"));
+        assert_eq!(strip("wrap/a", 2), None);
+        assert_eq!(strip("wrap/", 1), None);
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let path = SimplePath::new("/var/run/x");
+        assert_eq!(path.strip_prefix("/var").unwrap().as_str(), "run/x");
+        assert_eq!(path.strip_prefix("/var/run/").unwrap().as_str(), "x");
+        assert_eq!(path.strip_prefix("/var/run/x").unwrap().as_str(), "");
+        assert_eq!(path.strip_prefix("/").unwrap().as_str(), "var/run/x");
+        assert_eq!(path.strip_prefix("").unwrap().as_str(), "/var/run/x");
+        assert!(path.strip_prefix("/va").is_none());
+        assert!(path.strip_prefix("var").is_none());
+        assert!(path.strip_prefix("/var/run/x/y").is_none());
+
+        let path = SimplePath::new("var/run");
+        assert_eq!(path.strip_prefix("var").unwrap().as_str(), "run");
+        assert!(path.strip_prefix("/var").is_none());
+    }
+
+    #[test]
+    fn test_starts_ends_with() {
+        let path = SimplePath::new("/var/run/tmp");
+        assert!(path.starts_with("/var"));
+        assert!(path.starts_with("/var/run/"));
+        assert!(path.starts_with("/"));
+        assert!(path.starts_with(""));
+        assert!(!path.starts_with("/va"));
+        assert!(!path.starts_with("var"));
+        assert!(!path.starts_with("/var/run/tmp/x"));
+
+        assert!(path.ends_with("tmp"));
+        assert!(path.ends_with("run/tmp/"));
+        assert!(path.ends_with("/var/run/tmp"));
+        assert!(path.ends_with(""));
+        assert!(!path.ends_with("mp"));
+        assert!(!path.ends_with("/run/tmp"));
+        assert!(!path.ends_with("/x/var/run/tmp"));
+    }
+
+    #[test]
+    fn test_to_windows_string() {
+        let win = |s: &str| SimplePath::new(s).to_windows_string();
+        assert_eq!(win("/a/b/c"), "a\\b\\c");
+        assert_eq!(win("a//b/"), "a\\b");
+        assert_eq!(win("/C:/Users/backup"), "C:\\Users\\backup");
+        assert_eq!(win("C:\\Users"), "C:\\Users");
+        assert_eq!(win("single"), "single");
+        assert_eq!(win("/"), "");
+        assert_eq!(win(""), "");
+    }
+
+    #[test]
+    fn test_display() {
+        for p in ["/var//run/", "var\\run", "/", ""] {
+            let path = SimplePath::new(p);
+            assert_eq!(path.to_string(), path.as_str());
+        }
+        assert_eq!(format!("{}", SimplePath::new("/var//run/")), "/var/run");
+    }
+
+    #[test]
+    fn test_from_path() {
+        let path = SimplePath::from(Path::new("/var//run/tmp/"));
+        assert_eq!(path.as_str(), "/var/run/tmp");
+        let path = SimplePath::from(Path::new("var\\run\\tmp"));
+        assert_eq!(path.as_str(), "var/run/tmp");
+        let path = SimplePath::from(PathBuf::from("C:\\Users\\backup"));
+        assert_eq!(path.as_str(), "C:/Users/backup");
+        let path = SimplePath::from(PathBuf::from("/srv").join("backups"));
+        assert_eq!(path.as_str(), "/srv/backups");
+        assert_eq!(SimplePath::from(PathBuf::new()), SimplePath::default());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let path = SimplePath::new("/var/run");
+        let json = serde_json::to_string(&path).unwrap();
+        assert_eq!(json, "\"/var/run\"");
+
+        let path: SimplePath = serde_json::from_str("\"/var//run\\\\tmp/\"").unwrap();
+        assert_eq!(path.as_str(), "/var/run/tmp");
+    }
+
+    #[test]
+    fn test_ancestors_rev() {
+        let path = SimplePath::new("/var/run/tmp");
+        let ancestors: Vec<_> = path.ancestors_rev().collect();
+        assert_eq!(ancestors, ["/", "/var", "/var/run", "/var/run/tmp"]);
+        let ancestors: Vec<_> = path.ancestors_rev().rev().collect();
+        assert_eq!(ancestors, path.ancestors().collect::<Vec<_>>());
+
+        let path = SimplePath::new("var//run/");
+        let ancestors: Vec<_> = path.ancestors_rev().collect();
+        assert_eq!(ancestors, ["var", "var/run"]);
+
+        let path = SimplePath::new("/");
+        assert_eq!(path.ancestors_rev().collect::<Vec<_>>(), ["/"]);
+        let path = SimplePath::new("");
+        assert_eq!(path.ancestors_rev().next(), None);
+    }
+
+    #[test]
+    fn test_dir_ancestors() {
+        let dirs = |s: &str| {
+            let path = SimplePath::new(s);
+            path.dir_ancestors().map(str::to_owned).collect::<Vec<_>>()
+        };
+        assert_eq!(dirs("/var/run/tmp.sock"), ["/", "/var", "/var/run"]);
+        assert_eq!(dirs("/motd"), ["/"]);
+        assert_eq!(dirs("var//run/pid"), ["var", "var/run"]);
+        assert_eq!(dirs("motd"), [] as [&str; 0]);
+        assert_eq!(dirs("/"), [] as [&str; 0]);
+        assert_eq!(dirs(""), [] as [&str; 0]);
+
+        let path = SimplePath::new("/var/run/tmp.sock");
+        let dirs: Vec<_> = path.dir_ancestors().rev().collect();
+        assert_eq!(dirs, ["/var/run", "/var", "/"]);
+    }
+
+    #[test]
+    fn test_path_buf() {
+        let mut buf = SimplePathBuf::new();
+        buf.push("/srv");
+        buf.push("backups");
+        buf.push("archive.tar.gz");
+        assert_eq!(buf.as_str(), "/srv/backups/archive.tar.gz");
+
+        assert!(buf.set_extension("xz"));
+        assert_eq!(buf.as_str(), "/srv/backups/archive.tar.xz");
+        assert!(buf.set_extension(""));
+        assert_eq!(buf.as_str(), "/srv/backups/archive.tar");
+
+        assert!(buf.pop());
+        buf.push(".bashrc");
+        assert!(buf.set_extension("bak"));
+        assert_eq!(buf.as_str(), "/srv/backups/.bashrc.bak");
+
+        let mut buf = SimplePathBuf::from(SimplePath::new("/"));
+        assert!(!buf.set_extension("txt"));
+        buf.push("etc");
+        assert_eq!(buf.into_simple_path(), SimplePath::new("/etc"));
+    }
+
+    #[test]
+    fn test_parent() {
+        let path = SimplePath::new("/var/run/");
+        assert_eq!(path.parent().unwrap().as_str(), "/var");
+        let path = SimplePath::new("/var");
+        assert_eq!(path.parent().unwrap().as_str(), "/");
+        let path = SimplePath::new("var/run");
+        assert_eq!(path.parent().unwrap().as_str(), "var");
+        let path = SimplePath::new("var");
+        assert_eq!(path.parent().unwrap().as_str(), "");
+        assert!(SimplePath::new("///").parent().is_none());
+        assert!(SimplePath::new("").parent().is_none());
+    }
+
+    #[test]
+    fn test_ends_with_separator() {
+        assert!(SimplePath::ends_with_separator(&"etc/"));
+        assert!(SimplePath::ends_with_separator(&"etc\\"));
+        assert!(SimplePath::ends_with_separator(&"/"));
+        assert!(SimplePath::ends_with_separator(&"./etc//"));
+        assert!(!SimplePath::ends_with_separator(&"etc"));
+        assert!(!SimplePath::ends_with_separator(&"etc/."));
+        assert!(!SimplePath::ends_with_separator(&""));
+        // the path itself doesn't remember it
+        assert_eq!(SimplePath::new("etc/"), SimplePath::new("etc"));
+        assert!(!SimplePath::ends_with_separator(&SimplePath::new("etc/")));
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(SimplePath::new("/var/run/").file_name(), Some("run"));
+        assert_eq!(SimplePath::new("var").file_name(), Some("var"));
+        assert_eq!(SimplePath::new("a\\b/c").file_name(), Some("c"));
+        assert_eq!(SimplePath::new("a/b\\c\\").file_name(), Some("c"));
+        assert_eq!(SimplePath::new("a/..").file_name(), None);
+        assert_eq!(SimplePath::new("/").file_name(), None);
+        assert_eq!(SimplePath::new("").file_name(), None);
+    }
+
+    #[test]
+    fn test_with_file_name() {
+        let with = |s: &str, name: &str| SimplePath::new(s).with_file_name(name).to_string();
+        assert_eq!(with("/a/b/c", "d"), "/a/b/d");
+        assert_eq!(with("/a/b/c/", "d"), "/a/b/d");
+        assert_eq!(with("/a", "d"), "/d");
+        assert_eq!(with("a/b.txt", "c.tmp"), "a/c.tmp");
+        assert_eq!(with("a", "b"), "b");
+        assert_eq!(with("a/b", "c/d"), "a/c/d");
+        assert_eq!(with("a/b", "/c"), "/c");
+        assert_eq!(with("a/..", "b"), "a/../b");
+        assert_eq!(with("/", "d"), "d");
+        assert_eq!(with("", "d"), "d");
+    }
+
+    #[test]
+    fn test_extension() {
+        let path = SimplePath::new("/backup/archive.tar.gz");
+        assert_eq!(path.file_stem(), Some("archive.tar"));
+        assert_eq!(path.extension(), Some("gz"));
+
+        let path = SimplePath::new("/home/user/.bashrc");
+        assert_eq!(path.file_stem(), Some(".bashrc"));
+        assert_eq!(path.extension(), None);
+
+        let path = SimplePath::new("bin/ls");
+        assert_eq!(path.file_stem(), Some("ls"));
+        assert_eq!(path.extension(), None);
+
+        let path = SimplePath::new("var/data.d/");
+        assert_eq!(path.file_stem(), Some("data"));
+        assert_eq!(path.extension(), Some("d"));
+
+        let path = SimplePath::new("/");
+        assert_eq!(path.file_stem(), None);
+        assert_eq!(path.extension(), None);
+    }
+
+    #[test]
+    fn test_with_extension() {
+        let with = |s: &str, ext: &str| SimplePath::new(s).with_extension(ext).to_string();
+        assert_eq!(with("/a/archive.tar", "gz"), "/a/archive.gz");
+        assert_eq!(with("/a/archive", "tar"), "/a/archive.tar");
+        assert_eq!(with("/a/archive.tar.gz", ""), "/a/archive.tar");
+        assert_eq!(with("/a/archive", ""), "/a/archive");
+        assert_eq!(with("home/.bashrc", "bak"), "home/.bashrc.bak");
+        assert_eq!(with("home/.bashrc", ""), "home/.bashrc");
+        assert_eq!(with("data.d/", "old"), "data.old");
+        assert_eq!(with("/", "txt"), "/");
+        assert_eq!(with("a/..", "txt"), "a/..");
+    }
+
+    #[test]
+    fn test_push_extension() {
+        let push = |s: &str, ext: &str| SimplePath::new(s).push_extension(ext).to_string();
+        assert_eq!(push("/a/backup.tar", "gz"), "/a/backup.tar.gz");
+        assert_eq!(push("backup", "tar"), "backup.tar");
+        assert_eq!(push("home/.bashrc", "bak"), "home/.bashrc.bak");
+        assert_eq!(push("data.d/", "old"), "data.d.old");
+        assert_eq!(push("backup.tar", ""), "backup.tar");
+        assert_eq!(push("/", "gz"), "/");
+        assert_eq!(push("", "gz"), "");
+        assert_eq!(push("a/..", "gz"), "a/..");
+
+        let stacked = SimplePath::new("/srv/backup")
+            .push_extension("tar")
+            .push_extension("gz")
+            .push_extension("sha256sum");
+        assert_eq!(stacked.as_str(), "/srv/backup.tar.gz.sha256sum");
+        assert_eq!(stacked.extension(), Some("sha256sum"));
+        assert_eq!(stacked.file_stem(), Some("backup.tar.gz"));
+    }
+
+    #[test]
+    fn test_canonicalize_against() {
+        let base = SimplePath::new("/srv/restore");
+        let under = |s: &str| SimplePath::new(s).canonicalize_against(&base).to_string();
+        assert_eq!(under("etc/motd"), "/srv/restore/etc/motd");
+        assert_eq!(under("./a/./b/../c"), "/srv/restore/a/c");
+        assert_eq!(under("/etc/shadow"), "/srv/restore/etc/shadow");
+        assert_eq!(under("../../etc/shadow"), "/srv/restore/etc/shadow");
+        assert_eq!(under("a/../../../b"), "/srv/restore/b");
+        assert_eq!(under("/../.."), "/srv/restore");
+        assert_eq!(under(""), "/srv/restore");
+
+        let base = SimplePath::new("backups/./today/");
+        let under = |s: &str| SimplePath::new(s).canonicalize_against(&base).to_string();
+        assert_eq!(under("../x"), "backups/today/x");
+        assert_eq!(under("/"), "backups/today");
+        let root = SimplePath::new("/");
+        assert_eq!(
+            SimplePath::new("../etc")
+                .canonicalize_against(&root)
+                .as_str(),
+            "/etc"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(SimplePath::new("/a/b/../c").normalize().as_str(), "/a/c");
+        assert_eq!(SimplePath::new("a/./b").normalize().as_str(), "a/b");
+        assert_eq!(SimplePath::new("../../x").normalize().as_str(), "../../x");
+        assert_eq!(SimplePath::new("a/../../x").normalize().as_str(), "../x");
+        assert_eq!(SimplePath::new("/../x").normalize().as_str(), "/x");
+        assert_eq!(SimplePath::new("/a/..").normalize().as_str(), "/");
+        assert_eq!(SimplePath::new("./a/..").normalize().as_str(), "");
+    }
+
+    #[test]
+    fn test_normalize_str() {
+        for s in [
+            "/var/run/",
+            "var//run",
+            "\\srv\\restore",
+            "/",
+            "",
+            "a/./b/../c",
+            "../../x",
+            "/../x",
+            "./a/..",
+        ] {
+            assert_eq!(
+                normalize_str(s),
+                SimplePath::new(s).normalize().as_str(),
+                "{}",
+                s
+            );
+        }
+        assert_eq!(
+            normalize_str("/var/run/"),
+            SimplePath::new("/var/run/").as_str()
+        );
+        assert_eq!(normalize_str("a//b\\c"), "a/b/c");
+        assert_eq!(normalize_str("/a/b/../../.."), "/");
+        assert_eq!(normalize_str(String::from("a/../../b")), "../b");
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let p = |s: &str| SimplePath::new(s);
+        assert_eq!(p("/a/b/c").common_prefix(&p("/a/b/d")).as_str(), "/a/b");
+        assert_eq!(p("/a/b").common_prefix(&p("/a/b/d")).as_str(), "/a/b");
+        assert_eq!(p("/a/bc").common_prefix(&p("/a/b")).as_str(), "/a");
+        assert_eq!(p("/a/x").common_prefix(&p("/b/y")).as_str(), "/");
+        assert_eq!(p("a/b/c").common_prefix(&p("a/b/d")).as_str(), "a/b");
+        assert_eq!(p("a/x").common_prefix(&p("b/y")).as_str(), "");
+        assert_eq!(p("/a/b").common_prefix(&p("a/b")).as_str(), "");
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let rel = |a: &str, b: &str| {
+            SimplePath::new(a)
+                .relative_to(&SimplePath::new(b))
+                .map(|p| p.as_str().to_owned())
+        };
+        assert_eq!(rel("/a/b/c", "/a/x").as_deref(), Some("../b/c"));
+        assert_eq!(rel("/a/b/c", "/a").as_deref(), Some("b/c"));
+        assert_eq!(rel("/a", "/a/b/c").as_deref(), Some("../.."));
+        assert_eq!(rel("/a/b", "/a/b/").as_deref(), Some("."));
+        assert_eq!(rel("/x", "/").as_deref(), Some("x"));
+        assert_eq!(rel("a/b", "a/c").as_deref(), Some("../b"));
+        assert_eq!(rel("a/b", "x/../a").as_deref(), Some("b"));
+        assert_eq!(rel("a", "../b"), None);
+        assert_eq!(rel("/a/b", "a"), None);
+        assert_eq!(rel("a/b", "/a"), None);
+    }
+
+    #[test]
+    fn test_ancestors_size_hint() {
+        for p in [
+            "/var/run/tmp",
+            "///var/run//tmp/dir////",
+            "var//run/",
+            "////",
+            "a",
+            "",
+        ] {
+            let path = SimplePath::new(p);
+            let count = path.ancestors().count();
+            assert_eq!(path.ancestors().size_hint(), (count, Some(count)), "{}", p);
+            let raw = PathAncestors::new(p);
+            assert_eq!(raw.size_hint(), (count, Some(count)), "{}", p);
+
+            let mut iter = path.ancestors();
+            iter.next();
+            let left = count.saturating_sub(1);
+            assert_eq!(iter.size_hint(), (left, Some(left)), "{}", p);
+            iter.by_ref().for_each(drop);
+            assert_eq!(iter.size_hint(), (0, Some(0)));
+            assert_eq!(iter.next(), None);
+        }
+    }
+
     #[test]
     fn test_ancestors() {
         let path = SimplePath::new("/var/run/tmp/dir/");
@@ -233,7 +1454,6 @@ mod test {
 
         let path = SimplePath::new("");
         let mut iter = path.ancestors();
-        assert_eq!(iter.next(), Some(""));
         assert_eq!(iter.next(), None);
     }
 }
@@ -1,170 +1,1786 @@
 #![feature(trait_alias)]
 
+mod ssh_config;
+
 use std::collections::BTreeSet;
-use std::io::{Error, ErrorKind};
-use std::path::Path;
+use std::io::{Error, ErrorKind, IsTerminal, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_compat::CompatExt;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
 use async_io::Async;
-use async_ssh2_lite::{AsyncSession, AsyncSftp};
+use async_ssh2_lite::{
+    ssh2::{CheckResult, DisconnectCode, KnownHostFileKind, MethodType, Session},
+    AsyncSession, SessionConfiguration,
+};
 use async_tar::Archive;
-use clap::Parser;
-use futures::{io as fio, prelude::*};
+use clap::{ArgAction, Parser, ValueEnum};
+use encoding_rs::Encoding;
+use futures::{AsyncReadExt as _, Future, TryStreamExt};
+use globset::GlobSet;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::{StatusCode, Url};
 use tokio::{
     fs::File,
-    io::{self as tio, BufReader},
-    net::TcpStream,
-    sync::RwLock,
+    io::{self as tio, AsyncBufReadExt, BufReader},
+    net::{lookup_host, TcpStream},
+};
+use tokio_util::{io::StreamReader, sync::CancellationToken};
+use tracing::{debug, error, info, Level};
+
+use bakelite_ssh_backend::restore::{
+    build_globset, collect_dirs, remote_id, with_timeout, AbsolutePaths, ChownErrors, OnError,
+    Oversized, Owner, Symlinks, Transfer, Verify, DEFAULT_TEMP_DIR,
+};
+use bakelite_ssh_backend::{
+    restore_archives, RemoteTarget, RestoreError, RestoreOptions, RestoreStats, SimplePath,
 };
 
-use bakelite_ssh_backend::SimplePath;
+use crate::ssh_config::HostConfig;
 
 trait Readable = tio::AsyncRead + Unpin + Send + Sync;
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Whether ssh compresses the connection itself, whatever the tarfile's compression.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SshCompression {
+    On,
+    Off,
+}
+
+impl SshCompression {
+    /// What to offer the server for each direction, best first.
+    fn methods(self) -> &'static str {
+        match self {
+            SshCompression::On => "zlib@openssh.com,zlib,none",
+            SshCompression::Off => "none",
+        }
+    }
+}
+
+impl Compression {
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HostKeyChecking {
+    Yes,
+    No,
+    AcceptNew,
+}
+
+const EXIT_CODES: &str = "\
+EXIT CODES:
+    0    success
+    1    any other failure, such as an unreadable or unsafe archive
+    2    invalid arguments
+    3    could not connect to the server
+    4    could not authenticate
+    5    an upload failed partway through the restore, or with --on-error continue, any did
+    6    interrupted
+    7    the server ran out of disk space or quota
+    8    --pre-command or --post-command failed";
+
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
-    /// The tarfile to read from instead of stdin
+    /// The tarfile to read from instead of stdin, may be repeated to restore several in order
     #[clap(short, long)]
-    tarfile: Option<String>,
+    tarfile: Vec<String>,
 
-    /// The port to connect to the server on
-    #[clap(short, long, default_value_t = 22)]
-    port: u16,
+    /// Stream the tarfile from an http(s) URL instead of stdin
+    #[clap(long, value_parser = parse_url, conflicts_with = "tarfile")]
+    url: Option<Url>,
 
-    /// The username to connect with
-    #[clap(short, long, default_value_t = whoami::username())]
-    login: String,
+    /// The compression of the tarfile, detected from its magic bytes by default
+    #[clap(long, value_enum, default_value_t = Compression::Auto)]
+    compression: Compression,
 
-    /// The private key to authenticate with
+    /// Read the tarfile once for its directories and create them all before uploading any file
+    #[clap(long, requires = "tarfile")]
+    precreate_dirs: bool,
+
+    /// Only connect to the server over IPv4
+    #[clap(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect to the server over IPv6
+    #[clap(short = '6')]
+    ipv6: bool,
+
+    /// The port to connect to the server on, 22 unless set in the ssh config
     #[clap(short, long)]
-    identity: Option<String>,
+    port: Option<u16>,
+
+    /// The username to connect with, the local user unless set in the ssh config
+    #[clap(short, long)]
+    login: Option<String>,
+
+    /// The ssh config to read host aliases and defaults from instead of ~/.ssh/config
+    #[clap(short = 'F', long)]
+    ssh_config: Option<PathBuf>,
+
+    /// Reach the server through this bastion, given as [user@]HOST[:port]
+    #[clap(short = 'J', long, value_parser = parse_jump)]
+    jump: Option<HostSpec>,
+
+    /// Fall back to password authentication, prompting on the terminal if no value is given
+    #[clap(long, require_equals = true)]
+    password: Option<Option<String>>,
+
+    /// A private key to try before the ssh agent, can be given more than once
+    #[clap(short, long)]
+    identity: Vec<String>,
+
+    /// The passphrase of the identity files, prompting on the terminal if no value is given
+    #[clap(long, require_equals = true)]
+    passphrase: Option<Option<String>>,
+
+    /// Whether to verify the server's host key against ~/.ssh/known_hosts
+    #[clap(long, value_enum, default_value_t = HostKeyChecking::AcceptNew)]
+    strict_host_key_checking: HostKeyChecking,
+
+    /// Give up if the connection and handshake take longer than this many seconds
+    #[clap(long, default_value_t = 30)]
+    connect_timeout: u64,
+
+    /// Give up on a stalled read or write after this many seconds
+    #[clap(long)]
+    io_timeout: Option<u64>,
+
+    /// Give up on uploading a file after this many seconds however it is going, then retry it
+    #[clap(long)]
+    timeout_per_file: Option<u64>,
+
+    /// Send a keepalive message after this many idle seconds, 0 to disable
+    #[clap(long, default_value_t = 60)]
+    keepalive_interval: u64,
+
+    /// Compress the ssh connection with zlib. It costs cpu for nothing when the files are
+    /// already compressed, but can cut the traffic of a tree of text several times over
+    #[clap(long, value_enum, default_value = "off")]
+    ssh_compression: SshCompression,
+
+    /// The ciphers to offer the server, separated by commas and best first
+    #[clap(long, value_parser = parse_cipher)]
+    cipher: Option<String>,
+
+    /// The MACs to offer the server, separated by commas and best first
+    #[clap(long, value_parser = parse_mac)]
+    mac: Option<String>,
 
     /// The directory to change to upon login
     #[clap(short = 'C', long)]
     chdir: Option<String>,
 
-    /// The host to connect to, can also be specified as user@HOST
+    /// The number of files to upload in parallel
+    #[clap(short, long, default_value = "4")]
+    jobs: NonZeroUsize,
+
+    /// Read files up to this size into memory, so they can upload in parallel and be retried.
+    /// Bigger files are streamed one at a time with no retries. Takes an optional K, M or G suffix
+    #[clap(long, value_parser = parse_size, default_value = "16M")]
+    max_buffered: u64,
+
+    /// Skip files the archive says are larger than this, with an optional K, M or G suffix
+    #[clap(long, value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Whether a file over --max-file-size is skipped with a warning or stops the restore
+    #[clap(long, value_enum, default_value = "skip")]
+    oversized: Oversized,
+
+    /// Cap the upload rate across all jobs, in bytes per second with an optional K, M or G suffix
+    #[clap(long, value_parser = parse_size)]
+    limit_rate: Option<u64>,
+
+    /// The size of read buffers and of each write to the server, with an optional K or M suffix
+    #[clap(long, value_parser = parse_buffer_size, default_value = "256K")]
+    buffer_size: usize,
+
+    /// Retry an upload this many times after a timeout or dropped connection
+    #[clap(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Whether a file the server rejects stops the restore or is logged and skipped, exiting 5
+    #[clap(long, value_enum, default_value = "abort")]
+    on_error: OnError,
+
+    /// Show a progress bar on stderr, the default when stdout is a terminal
+    #[clap(long, overrides_with = "no_progress")]
+    progress: bool,
+
+    /// Never show a progress bar
+    #[clap(long, overrides_with = "progress")]
+    no_progress: bool,
+
+    /// Log every entry as it is written, twice to log even more
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log errors
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// How to send file contents; sftp does not need the size up front
+    #[clap(long, value_enum, default_value = "scp")]
+    transfer: Transfer,
+
+    /// Checksum each file on the remote after uploading it and compare with the archive
+    #[clap(long, value_enum)]
+    verify: Option<Verify>,
+
+    /// Set a variable for the commands run on the server, like the checksums of --verify, may be
+    /// repeated; the server has to accept it
+    #[clap(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Run this shell command on the server before restoring, like stopping a service; the
+    /// restore doesn't start unless it exits 0
+    #[clap(long)]
+    pre_command: Option<String>,
+
+    /// Run this shell command on the server once the restore succeeds
+    #[clap(long)]
+    post_command: Option<String>,
+
+    /// Run --post-command after a failed or interrupted restore too
+    #[clap(long, requires = "post-command")]
+    post_always: bool,
+
+    /// Upload a sha256sum list of every file restored to this remote path, for sha256sum -c
+    #[clap(long, value_name = "REMOTE_PATH")]
+    write_checksums: Option<String>,
+
+    /// Keep the modification times recorded in the archive [default]. Unlike scp there is no -p
+    /// for this, -p is --port
+    #[clap(long, overrides_with = "no_preserve_times")]
+    preserve_times: bool,
+
+    /// Leave uploaded files with the time they were written on the remote
+    #[clap(long, overrides_with = "preserve_times")]
+    no_preserve_times: bool,
+
+    /// Apply the permissions recorded in the archive instead of 0644 for files
+    #[clap(long)]
+    preserve_permissions: bool,
+
+    /// The octal mode to create missing directories with
+    #[clap(long, value_parser = parse_mode, default_value = "0755")]
+    dir_mode: u32,
+
+    /// Clear these octal mode bits from every file and directory written, like 022
+    #[clap(long, value_parser = parse_umask, default_value = "0")]
+    umask: u32,
+
+    /// Give restored files and directories to this user:group, by name or number
+    #[clap(long, value_parser = parse_owner)]
+    chown: Option<OwnerSpec>,
+
+    /// Apply the uid and gid recorded in the archive, --chown wins where both are given
+    #[clap(long)]
+    preserve_owner: bool,
+
+    /// Whether an owner the server refuses to set only warns or stops the restore
+    #[clap(long, value_enum, default_value = "warn")]
+    chown_errors: ChownErrors,
+
+    /// Where uploads are written before being renamed into place, under the destination unless
+    /// absolute; it has to be on the same filesystem
+    #[clap(long, value_parser = parse_temp_dir, default_value = DEFAULT_TEMP_DIR)]
+    temp_dir: SimplePath,
+
+    /// Remove files left in the temp directory by an interrupted run [default]
+    #[clap(long, overrides_with = "no_clean_tmp")]
+    clean_tmp: bool,
+
+    /// Leave the contents of the temp directory alone
+    #[clap(long, overrides_with = "clean_tmp")]
+    no_clean_tmp: bool,
+
+    /// Skip files whose size and modification time, or checksum with --verify, already match
+    #[clap(long)]
+    skip_unchanged: bool,
+
+    /// Never overwrite a file or link already on the remote, whatever it holds
+    #[clap(long, conflicts_with = "skip-unchanged")]
+    no_clobber: bool,
+
+    /// Pick up an interrupted restore, skipping files the remote already has at full size
+    #[clap(long)]
+    resume: bool,
+
+    /// Delete remote files and directories below the destination that are not in the archive
+    #[clap(long)]
+    delete: bool,
+
+    /// Decode file names in the archive from this character set, like shift_jis or latin1
+    #[clap(long, value_parser = parse_charset)]
+    remote_charset: Option<&'static Encoding>,
+
+    /// Only log the directories and files that would be written to the remote
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Strip this many leading components from entry paths, skipping shorter entries
+    #[clap(long, default_value_t = 0)]
+    strip_components: usize,
+
+    /// Where entries with an absolute path go, under the destination by default
+    #[clap(long, value_enum, default_value = "reroot")]
+    absolute_paths: AbsolutePaths,
+
+    /// Recreate links, skip them, or copy the file they point to when the archive has it
+    #[clap(long, value_enum, default_value = "recreate")]
+    symlinks: Symlinks,
+
+    /// Only restore entries whose path after --strip-components matches this glob, may be repeated
+    #[clap(long)]
+    include: Vec<String>,
+
+    /// Skip entries whose path after --strip-components matches this glob, even if included
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// Write a JSON record of every file restored to this path once the restore succeeds
+    #[cfg(feature = "manifest")]
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// The host to connect to, can also be specified as [user@]HOST[:port][:/path]
+    #[clap(value_parser = parse_host)]
+    host: HostSpec,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HostSpec {
+    login: Option<String>,
     host: String,
+    port: Option<u16>,
+    path: Option<String>,
 }
 
-fn wrap_readable<'a>(r: impl Readable + 'a) -> BufReader<Box<dyn Readable + 'a>> {
-    BufReader::with_capacity(8 * 1024, Box::new(r))
+fn parse_host(spec: &str) -> Result<HostSpec, String> {
+    let (login, rest) = match spec.split_once('@') {
+        Some((login, rest)) => (Some(login.to_owned()), rest),
+        None => (None, spec),
+    };
+    if rest.parse::<Ipv6Addr>().is_ok() {
+        return Ok(HostSpec {
+            login,
+            host: rest.to_owned(),
+            port: None,
+            path: None,
+        });
+    }
+    let (host, rest) = match rest.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .ok_or_else(|| format!("missing closing ] in {}", spec))?,
+        None => rest.split_at(rest.find(':').unwrap_or(rest.len())),
+    };
+    if host.is_empty() {
+        return Err(format!("no host given in {}", spec));
+    }
+    let is_port = |p: &str| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit());
+    let (port, path) = match rest.strip_prefix(':') {
+        None if rest.is_empty() => (None, None),
+        None => return Err(format!("unexpected {} after host in {}", rest, spec)),
+        Some(rest) => match rest.split_once(':') {
+            Some((port, path)) if is_port(port) => (Some(port), Some(path)),
+            _ if is_port(rest) => (Some(rest), None),
+            _ => (None, Some(rest)),
+        },
+    };
+    let port = port
+        .map(|p| p.parse().map_err(|e| format!("invalid port {}: {}", p, e)))
+        .transpose()?;
+    Ok(HostSpec {
+        login,
+        host: host.to_owned(),
+        port,
+        path: path.filter(|p| !p.is_empty()).map(str::to_owned),
+    })
 }
 
-async fn connect_from_args(
-    args: &Args,
-) -> Result<AsyncSession<std::net::TcpStream>, Box<dyn std::error::Error>> {
-    let (login, host) = match args.host.split_once('@') {
-        Some(x) => x,
-        None => (args.login.as_str(), args.host.as_str()),
+fn parse_jump(spec: &str) -> Result<HostSpec, String> {
+    if spec.contains(',') {
+        return Err(format!("only one jump host is supported, got {}", spec));
+    }
+    let jump = parse_host(spec)?;
+    match jump.path {
+        Some(_) => Err(format!("a jump host cannot have a path: {}", spec)),
+        None => Ok(jump),
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(parsed),
+        scheme => Err(format!("unsupported scheme {} in {}", scheme, url)),
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&size[..i], c.to_ascii_uppercase()),
+        _ => (size, 'B'),
+    };
+    let shift = match unit {
+        'B' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => return Err(format!("unknown unit {} in {}", unit, size)),
     };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 && !digits.starts_with('+') => n
+            .checked_mul(1 << shift)
+            .ok_or_else(|| format!("{} is too large", size)),
+        _ => Err(format!("{} is not a size like 5M", size)),
+    }
+}
 
-    let sock = TcpStream::connect((host, args.port)).await?;
-    let sock = Async::new(sock.into_std()?)?;
-    let mut session = AsyncSession::new(sock, None)?;
+fn parse_buffer_size(size: &str) -> Result<usize, String> {
+    // every job and decoder holds one, so keep a typo from eating all memory
+    match parse_size(size)? {
+        n if n <= 64 << 20 => Ok(n as usize),
+        _ => Err(format!("buffer size {} is larger than 64M", size)),
+    }
+}
 
-    session.handshake().await?;
-    session.userauth_agent_with_try_next(login).await?;
-    Ok(session)
+fn parse_charset(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown character set {}", label))
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(m) if !digits.starts_with('+') && m <= 0o7777 => Ok(m),
+        _ => Err(format!("{} is not an octal mode like 0755", mode)),
+    }
+}
+
+/// A user or group as given on the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Id {
+    Number(u32),
+    Name(String),
+}
+
+/// Either side of a `user:group` may be left out, like `user`, `user:` or `:group`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct OwnerSpec {
+    user: Option<Id>,
+    group: Option<Id>,
+}
+
+fn parse_owner(owner: &str) -> Result<OwnerSpec, String> {
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+    let id = |id: &str| match id.parse() {
+        _ if id.is_empty() => Ok(None),
+        Ok(n) if !id.starts_with('+') => Ok(Some(Id::Number(n))),
+        _ if id.contains(|c: char| c == ':' || c.is_whitespace()) => {
+            Err(format!("{} is not an owner like user:group", owner))
+        }
+        _ => Ok(Some(Id::Name(id.to_owned()))),
+    };
+    match (id(user)?, id(group)?) {
+        (None, None) => Err(format!("{} is not an owner like user:group", owner)),
+        (user, group) => Ok(OwnerSpec { user, group }),
+    }
+}
+
+/// Turns the names in `spec` into the remote's numeric ids.
+async fn resolve_owner(remote: &RemoteTarget, spec: &OwnerSpec) -> Result<Owner, Error> {
+    let mut ids = [None, None];
+    for (id, (spec, group)) in ids
+        .iter_mut()
+        .zip([(&spec.user, false), (&spec.group, true)])
+    {
+        *id = match spec {
+            Some(Id::Number(n)) => Some(*n),
+            Some(Id::Name(name)) => Some(remote_id(remote, name, group).await?),
+            None => None,
+        };
+    }
+    let [uid, gid] = ids;
+    Ok(Owner { uid, gid })
+}
+
+/// Checks every algorithm in `list` is one libssh2 was built with.
+fn parse_algorithms(list: &str, method: MethodType, what: &str) -> Result<String, String> {
+    let supported = Session::new()
+        .and_then(|session| session.supported_algs(method))
+        .map_err(|e| e.to_string())?;
+    match list.split(',').find(|alg| !supported.contains(alg)) {
+        Some(alg) => Err(format!(
+            "{:?} is not a {} libssh2 supports, it has {}",
+            alg,
+            what,
+            supported.join(",")
+        )),
+        None => Ok(list.to_owned()),
+    }
+}
+
+fn parse_cipher(list: &str) -> Result<String, String> {
+    parse_algorithms(list, MethodType::CryptCs, "cipher")
+}
+
+fn parse_mac(list: &str) -> Result<String, String> {
+    parse_algorithms(list, MethodType::MacCs, "MAC")
+}
+
+fn parse_env(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+            Ok((key.to_owned(), value.to_owned()))
+        }
+        _ => Err(format!("{} is not a variable like KEY=VALUE", pair)),
+    }
+}
+
+fn parse_temp_dir(dir: &str) -> Result<SimplePath, String> {
+    // stale files are cleaned out of it, so it can't be the destination itself
+    match SimplePath::try_new(dir).map_err(|e| e.to_string())? {
+        pth if pth.is_relative() && pth.normalize().is_empty() => {
+            Err(format!("{} is the destination, not a directory in it", dir))
+        }
+        pth => Ok(pth),
+    }
+}
+
+fn parse_umask(umask: &str) -> Result<u32, String> {
+    match parse_mode(umask) {
+        Ok(m) if m <= 0o777 => Ok(m),
+        _ => Err(format!("{} is not an octal umask like 022", umask)),
+    }
+}
+
+fn wrap_readable<'a>(r: impl Readable + 'a, capacity: usize) -> BufReader<Box<dyn Readable + 'a>> {
+    BufReader::with_capacity(capacity, Box::new(r))
+}
+
+/// Starts drawing `progress`, which begins hidden so logging can be set up before the input's
+/// length is known.
+fn show_progress(progress: &ProgressBar, args: &Args, len: Option<u64>) {
+    if args.no_progress || !(args.progress || std::io::stdout().is_terminal()) {
+        return;
+    }
+    let style = match len {
+        Some(len) => {
+            progress.set_length(len);
+            ProgressStyle::with_template(
+                "{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} remaining)",
+            )
+        }
+        None => ProgressStyle::with_template("{spinner} {bytes} ({bytes_per_sec})"),
+    };
+    progress.set_style(style.unwrap());
+    progress.set_draw_target(ProgressDrawTarget::stderr());
+}
+
+/// Writes log lines to stderr with the progress bar cleared out of the way.
+struct LogWriter(ProgressBar);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.suspend(|| std::io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        std::io::stderr().flush()
+    }
 }
 
-async fn mkdir_r<T, P: Into<SimplePath>>(
-    sftp: &AsyncSftp<T>,
-    pth: P,
-    seen_paths: Arc<RwLock<BTreeSet<String>>>,
+fn init_logging(args: &Args, progress: ProgressBar) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => Level::ERROR,
+        (false, 0) => Level::INFO,
+        (false, 1) => Level::DEBUG,
+        (false, _) => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(move || LogWriter(progress.clone()))
+        .init();
+}
+
+/// Starts downloading `url`, redirects are followed but anything other than 200 is an error.
+async fn open_url(url: &Url) -> Result<(Box<dyn Readable>, Option<u64>), Error> {
+    let resp = reqwest::get(url.clone())
+        .await
+        .map_err(|e| Error::other(format!("could not fetch {}: {}", url, e)))?;
+    if resp.status() != StatusCode::OK {
+        return Err(Error::other(format!(
+            "could not fetch {}: server returned {}",
+            url,
+            resp.status()
+        )));
+    }
+    let len = resp.content_length();
+    let body = StreamReader::new(resp.bytes_stream().map_err(Error::other));
+    Ok((Box::new(body), len))
+}
+
+async fn decompress<'a>(
+    mut reader: BufReader<Box<dyn Readable + 'a>>,
+    compression: Compression,
+    buffer_size: usize,
+) -> Result<BufReader<Box<dyn Readable + 'a>>, std::io::Error> {
+    let compression = match compression {
+        Compression::Auto => Compression::detect(reader.fill_buf().await?),
+        c => c,
+    };
+    Ok(match compression {
+        Compression::Gzip => wrap_readable(GzipDecoder::new(reader), buffer_size),
+        Compression::Zstd => wrap_readable(ZstdDecoder::new(reader), buffer_size),
+        Compression::Auto | Compression::None => reader,
+    })
+}
+
+fn known_hosts_path() -> Result<PathBuf, std::io::Error> {
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| Error::other("cannot locate known_hosts without $HOME"))?;
+    Ok(Path::new(&home).join(".ssh").join("known_hosts"))
+}
+
+fn verify_host_key<S>(
+    session: &AsyncSession<S>,
+    host: &str,
+    port: u16,
+    policy: HostKeyChecking,
 ) -> Result<(), std::io::Error> {
-    let pth = pth.into();
-    let ancestors: Vec<_> = pth
-        .ancestors()
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-        .filter(|&p| !p.is_empty())
-        .collect();
-    // println!("ancestors: {:?}", ancestors);
-    for pth in ancestors {
-        if pth.is_empty() || seen_paths.read().await.contains(pth) {
-            continue;
+    if policy == HostKeyChecking::No {
+        return Ok(());
+    }
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| Error::other("server did not send a host key"))?;
+    let path = known_hosts_path()?;
+
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(Error::other(format!(
+            "host key for {} does not match the one in {}, refusing to connect",
+            host,
+            path.display()
+        ))),
+        CheckResult::NotFound if policy == HostKeyChecking::AcceptNew => {
+            let name = if port == 22 {
+                host.to_owned()
+            } else {
+                format!("[{}]:{}", host, port)
+            };
+            // append rather than rewrite, libssh2 drops entries it can't parse
+            let mut new_host = session.known_hosts()?;
+            new_host.add(&name, key, "", key_type.into())?;
+            let mut line = String::new();
+            for h in new_host.hosts()? {
+                line += &new_host.write_string(&h, KnownHostFileKind::OpenSSH)?;
+            }
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?
+                .write_all(line.as_bytes())?;
+            info!("added {} to {}", name, path.display());
+            Ok(())
         }
-        let npth = Path::new(pth);
-        match sftp.stat(npth).await {
-            Ok(_) => (),
-            Err(_) => {
-                // println!("mkdir {}", pth);
-                sftp.mkdir(npth, 0o755).await?
+        CheckResult::NotFound => Err(Error::other(format!(
+            "no host key for {} in {}",
+            host,
+            path.display()
+        ))),
+        CheckResult::Failure => Err(Error::other(format!(
+            "could not check the host key for {}",
+            host
+        ))),
+    }
+}
+
+fn prompt_secret(
+    secret: &Option<Option<String>>,
+    prompt: String,
+) -> Result<Option<String>, std::io::Error> {
+    match secret {
+        Some(Some(secret)) => Ok(Some(secret.clone())),
+        Some(None) => rpassword::prompt_password(prompt).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn identity_file(identity: &str) -> Result<&Path, std::io::Error> {
+    let path = Path::new(identity);
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!("identity file {} does not exist", identity),
+        ))
+    }
+}
+
+/// Runs `attempt` on each item until one succeeds, giving the last error if none did and
+/// `None` if there was nothing to try.
+async fn try_in_order<'a, T, F, Fut>(items: &'a [T], mut attempt: F) -> Option<Result<(), Error>>
+where
+    F: FnMut(&'a T) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut last = None;
+    for item in items {
+        match attempt(item).await {
+            Ok(()) => return Some(Ok(())),
+            Err(e) => {
+                debug!("{}", e);
+                last = Some(Err(e));
             }
         }
-        {
-            let mut seen_paths = seen_paths.write().await;
-            seen_paths.insert(pth.to_owned());
+    }
+    last
+}
+
+async fn authenticate_pubkey(
+    session: &AsyncSession<std::net::TcpStream>,
+    login: &str,
+    identity: &str,
+    args: &Args,
+) -> Result<(), std::io::Error> {
+    let path = identity_file(identity)?;
+    let prompt = format!("Enter passphrase for key '{}': ", identity);
+    let passphrase = prompt_secret(&args.passphrase, prompt)?;
+    session
+        .userauth_pubkey_file(login, None, path, passphrase.as_deref())
+        .await
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("could not authenticate with {}: {}", identity, e),
+            )
+        })
+}
+
+async fn authenticate(
+    session: &AsyncSession<std::net::TcpStream>,
+    login: &str,
+    host: &str,
+    identities: &[String],
+    args: &Args,
+) -> Result<(), std::io::Error> {
+    let keys = try_in_order(identities, |identity| {
+        authenticate_pubkey(session, login, identity, args)
+    })
+    .await;
+    let result = match keys {
+        Some(Ok(())) => Ok(()),
+        // the agent's error says less than why the keys we were given failed
+        Some(Err(e)) => session
+            .userauth_agent_with_try_next(login)
+            .await
+            .map_err(|_| e),
+        None => session.userauth_agent_with_try_next(login).await,
+    };
+    if let Err(e) = result {
+        let prompt = format!("{}@{}'s password: ", login, host);
+        match prompt_secret(&args.password, prompt)? {
+            Some(password) => session.userauth_password(login, &password).await?,
+            None => return Err(e),
         }
     }
     Ok(())
 }
 
+async fn connect_tcp(
+    host: &str,
+    port: u16,
+    ipv4: bool,
+    ipv6: bool,
+) -> Result<TcpStream, std::io::Error> {
+    let mut last_err = None;
+    for addr in lookup_host((host, port)).await? {
+        if (ipv4 && !addr.is_ipv4()) || (ipv6 && !addr.is_ipv6()) {
+            continue;
+        }
+        match TcpStream::connect(addr).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("no usable address found for {}", host),
+        )
+    }))
+}
+
+/// Where and how to log in to one hop of the connection.
+#[derive(Debug, PartialEq, Eq)]
+struct Hop {
+    login: String,
+    host: String,
+    port: u16,
+    identities: Vec<String>,
+    proxy_jump: Option<String>,
+}
+
+/// Fills in what the command line left out from the ssh config, then from
+/// the defaults ssh itself would use.
+fn resolve_hop(
+    spec: &HostSpec,
+    login: Option<&str>,
+    port: Option<u16>,
+    args: &Args,
+) -> Result<Hop, RestoreError> {
+    let config = match args.ssh_config.clone().or_else(ssh_config::default_path) {
+        Some(path) => ssh_config::load(&path, &spec.host).map_err(RestoreError::Connect)?,
+        None => HostConfig::default(),
+    };
+    Ok(Hop {
+        login: spec
+            .login
+            .as_deref()
+            .or(login)
+            .map(str::to_owned)
+            .or(config.user)
+            .unwrap_or_else(whoami::username),
+        host: config.host_name.unwrap_or_else(|| spec.host.clone()),
+        port: spec.port.or(port).or(config.port).unwrap_or(22),
+        // like ssh, a configured key that doesn't exist is skipped rather than fatal
+        identities: if args.identity.is_empty() {
+            config
+                .identity_files
+                .into_iter()
+                .filter(|path| path.is_file())
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect()
+        } else {
+            args.identity.clone()
+        },
+        proxy_jump: config.proxy_jump,
+    })
+}
+
+async fn handshake(
+    sock: std::net::TcpStream,
+    args: &Args,
+) -> Result<AsyncSession<std::net::TcpStream>, std::io::Error> {
+    let sock = Async::new(sock)?;
+    let mut config = SessionConfiguration::new();
+    if let Some(secs) = args.io_timeout {
+        config.set_timeout(secs.saturating_mul(1000).try_into().unwrap_or(u32::MAX));
+    }
+    if args.keepalive_interval > 0 {
+        config.set_keepalive(
+            false,
+            args.keepalive_interval.try_into().unwrap_or(u32::MAX),
+        );
+    }
+    config.set_compress(args.ssh_compression == SshCompression::On);
+    let mut session = AsyncSession::new(sock, Some(config))?;
+    set_compression(&session, args.ssh_compression).await?;
+    let prefs = [
+        (&args.cipher, [MethodType::CryptCs, MethodType::CryptSc]),
+        (&args.mac, [MethodType::MacCs, MethodType::MacSc]),
+    ];
+    for (algorithms, methods) in prefs {
+        if let Some(algorithms) = algorithms {
+            for method in methods {
+                session.method_pref(method, algorithms).await?;
+            }
+        }
+    }
+    session.handshake().await.map_err(explain_negotiation)?;
+    Ok(session)
+}
+
+/// libssh2 reports a server with no cipher, MAC or key exchange in common with us as just a
+/// failed key exchange, so this says what is likely wrong.
+fn explain_negotiation(e: Error) -> Error {
+    if e.to_string().contains("Unable to exchange encryption keys") {
+        Error::new(
+            e.kind(),
+            format!(
+                "no algorithms in common with the server, --cipher and --mac can offer others: {}",
+                e
+            ),
+        )
+    } else {
+        e
+    }
+}
+
+/// libssh2 only offers zlib with the compress flag set, the preference makes sure it is the one
+/// picked when the server has it.
+async fn set_compression(
+    session: &AsyncSession<std::net::TcpStream>,
+    compression: SshCompression,
+) -> Result<(), std::io::Error> {
+    for method in [MethodType::CompCs, MethodType::CompSc] {
+        session.method_pref(method, compression.methods()).await?;
+    }
+    Ok(())
+}
+
+async fn login(
+    session: &AsyncSession<std::net::TcpStream>,
+    hop: &Hop,
+    args: &Args,
+) -> Result<(), RestoreError> {
+    verify_host_key(session, &hop.host, hop.port, args.strict_host_key_checking)
+        .map_err(RestoreError::Connect)?;
+    authenticate(session, &hop.login, &hop.host, &hop.identities, args)
+        .await
+        .map_err(RestoreError::Auth)
+}
+
+/// Opens a channel to `host` through `jump` and hands back a socket carrying
+/// it. libssh2 can only run a session over a real socket, so the channel is
+/// pumped through a loopback connection until either end closes.
+async fn tunnel(
+    jump: AsyncSession<std::net::TcpStream>,
+    host: &str,
+    port: u16,
+) -> Result<std::net::TcpStream, std::io::Error> {
+    let channel = jump.channel_direct_tcpip(host, port, None).await?;
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let sock = std::net::TcpStream::connect(listener.local_addr()?)?;
+    let (peer, addr) = listener.accept()?;
+    // anything else on this machine could have raced us to the listener
+    if addr != sock.local_addr()? {
+        return Err(Error::other("unexpected connection to the jump tunnel"));
+    }
+    let peer = Async::new(peer)?;
+    tokio::spawn(async move {
+        let (mut from_channel, mut to_channel) = channel.split();
+        let (mut from_peer, mut to_peer) = (&peer, &peer);
+        futures::future::select(
+            futures::io::copy(&mut from_channel, &mut to_peer),
+            futures::io::copy(&mut from_peer, &mut to_channel),
+        )
+        .await;
+        let _ = jump.disconnect(None, "goodbye", None).await;
+    });
+    Ok(sock)
+}
+
+/// Connects and logs in to the server, through the jump host from `--jump`
+/// or the ssh config's ProxyJump if there is one. Each hop is authenticated
+/// the same way: each identity file from `--identity` or else the hop's ssh
+/// config in turn, then the ssh agent, then the password if `--password` was
+/// given. The login and port of the jump host come from its spec and its own
+/// ssh config, never from `--login` and `--port`, and its host key is checked
+/// like the server's.
+async fn connect_from_args(args: &Args) -> Result<AsyncSession<std::net::TcpStream>, RestoreError> {
+    let target = resolve_hop(&args.host, args.login.as_deref(), args.port, args)?;
+    let jump = match (args.jump.clone(), target.proxy_jump.as_deref()) {
+        (Some(jump), _) => Some(jump),
+        (None, Some(jump)) => {
+            Some(parse_jump(jump).map_err(|e| RestoreError::Connect(Error::other(e)))?)
+        }
+        (None, None) => None,
+    };
+    let jump = jump
+        .map(|jump| resolve_hop(&jump, None, None, args))
+        .transpose()?;
+    for hop in jump.iter().chain([&target]) {
+        for identity in &hop.identities {
+            identity_file(identity).map_err(RestoreError::Auth)?;
+        }
+    }
+
+    let timeout = Some(args.connect_timeout);
+    let connect = |hop: &Hop| {
+        let (host, port) = (hop.host.clone(), hop.port);
+        with_timeout(timeout, "connecting", async move {
+            connect_tcp(&host, port, args.ipv4, args.ipv6)
+                .await?
+                .into_std()
+        })
+    };
+    let sock = match jump {
+        Some(jump) => {
+            let sock = connect(&jump).await.map_err(RestoreError::Connect)?;
+            let bastion = with_timeout(timeout, "connecting", handshake(sock, args))
+                .await
+                .map_err(RestoreError::Connect)?;
+            login(&bastion, &jump, args).await?;
+            info!("connected to jump host {}", jump.host);
+            with_timeout(
+                timeout,
+                "connecting",
+                tunnel(bastion, &target.host, target.port),
+            )
+            .await
+            .map_err(RestoreError::Connect)?
+        }
+        None => connect(&target).await.map_err(RestoreError::Connect)?,
+    };
+    let session = with_timeout(timeout, "connecting", handshake(sock, args))
+        .await
+        .map_err(RestoreError::Connect)?;
+    login(&session, &target, args).await?;
+    Ok(session)
+}
+
+#[cfg(feature = "manifest")]
+fn write_manifest(path: &Path, stats: &RestoreStats) -> Result<(), Error> {
+    let json = serde_json::to_vec_pretty(stats)?;
+    // renamed into place so a reader never sees half a manifest
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn spawn_keepalive(remote: RemoteTarget, interval: u64) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // the first tick completes immediately, right after the handshake
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if remote.session().keepalive_send().await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     let args = Args::parse();
-    let reader = match args.tarfile.as_ref() {
-        Some(f) => wrap_readable(File::open(f).await?),
-        None => wrap_readable(tio::stdin()),
-    };
-    let archive = Archive::new(reader.compat());
-
-    let session = connect_from_args(&args).await?;
-    let sftp = Arc::new(session.sftp().await?);
-
-    println!("connected!");
-
-    let base_path = SimplePath::new(args.chdir.unwrap_or(".".to_owned()));
-    let seen_paths = Arc::new(RwLock::new(BTreeSet::<String>::new()));
-
-    let tmp_path = base_path.join(".tmp");
-    mkdir_r(&sftp, tmp_path.as_str(), seen_paths.clone()).await?;
-
-    archive
-        .entries()?
-        .try_for_each(|mut ent| {
-            let base_path = &base_path;
-            let seen_paths = seen_paths.clone();
-            let sftp = sftp.clone();
-            let session = &session;
-            async move {
-                if !ent.header().entry_type().is_file() {
-                    return Ok(());
-                }
-                let dst = base_path.join(ent.path()?.to_string_lossy());
-                mkdir_r(&sftp, dst.ancestors().skip(1).next().unwrap(), seen_paths).await?;
-
-                let sz = ent.header().size()?;
-                println!("put {} [{} bytes]", dst.as_str(), sz);
-
-                let mut ch = session
-                    .scp_send(Path::new(dst.as_str()), 0o644, sz, None)
-                    .await
-                    .map_err(|e| {
-                        Error::new(ErrorKind::Other, format!("could not open file: {:?}", e))
-                    })?;
-                let bytes = fio::copy(&mut ent, &mut ch).await.map_err(|e| {
-                    Error::new(ErrorKind::Other, format!("could not write bytes: {:?}", e))
-                })?;
-                ch.close().await?;
-
-                if bytes == sz {
-                    Ok(())
-                } else {
-                    Err(Error::new(
-                        ErrorKind::Other,
-                        format!("expected {} bytes but only wrote {}", sz, bytes),
-                    ))
-                }
+    let progress = ProgressBar::hidden();
+    init_logging(&args, progress.clone());
+    match run(args, progress).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::from(exit_code(e.as_ref()))
+        }
+    }
+}
+
+/// Maps a failure to the code in `EXIT_CODES`. 2 is left to clap, which uses it for bad
+/// arguments.
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> u8 {
+    match e.downcast_ref::<RestoreError>() {
+        Some(RestoreError::Connect(_)) => 3,
+        Some(RestoreError::Auth(_)) => 4,
+        Some(
+            RestoreError::Sftp { .. }
+            | RestoreError::Transfer { .. }
+            | RestoreError::Checksum { .. }
+            | RestoreError::Incomplete { .. },
+        ) => 5,
+        Some(RestoreError::Interrupted) => 6,
+        Some(RestoreError::OutOfSpace { .. }) => 7,
+        Some(RestoreError::Hook { .. }) => 8,
+        Some(RestoreError::Archive(_) | RestoreError::Path(_)) | None => 1,
+    }
+}
+
+async fn run(args: Args, progress: ProgressBar) -> Result<(), Box<dyn std::error::Error>> {
+    let include = build_globset(&args.include)?;
+    let exclude = build_globset(&args.exclude)?;
+    let mut inputs: Vec<(Box<dyn Readable>, _)> = Vec::new();
+    match (&args.tarfile[..], args.url.as_ref()) {
+        ([], Some(url)) => inputs.push(open_url(url).await?),
+        ([], None) => inputs.push((Box::new(tio::stdin()), None)),
+        (files, _) => {
+            // all opened before connecting, so a typo in the last one fails early
+            for f in files {
+                let file = File::open(f).await?;
+                let len = file.metadata().await?.len();
+                inputs.push((Box::new(file), Some(len)));
             }
-        })
-        .await?;
+        }
+    }
+    show_progress(&progress, &args, inputs.iter().map(|(_, len)| *len).sum());
+    let mut archives = Vec::new();
+    for (input, _) in inputs {
+        let reader = wrap_readable(progress.wrap_async_read(input), args.buffer_size);
+        let reader = decompress(reader, args.compression, args.buffer_size).await?;
+        archives.push(Archive::new(reader.compat()));
+    }
 
-    session.disconnect(None, "goodbye", None).await?;
+    let session = Arc::new(connect_from_args(&args).await?);
+    info!("connected to {}", args.host.host);
+    let remote = match RemoteTarget::new(session.clone()).await {
+        Ok(remote) => remote.with_env(args.env.clone()),
+        Err(e) => {
+            let result = Err(e.into());
+            let code = Some(DisconnectCode::ByApplication);
+            let _ = session
+                .disconnect(code, &disconnect_message(&result), None)
+                .await;
+            return result;
+        }
+    };
+    drop(session);
+    let keepalive = (args.keepalive_interval > 0)
+        .then(|| spawn_keepalive(remote.clone(), args.keepalive_interval));
+    let result = restore(&args, &remote, archives, include, exclude).await;
 
+    progress.finish_and_clear();
+    if let Some(keepalive) = keepalive {
+        keepalive.abort();
+        let _ = keepalive.await;
+    }
+    // tell the server why instead of leaving it to time the connection out
+    let code = result.is_err().then_some(DisconnectCode::ByApplication);
+    let disconnected = remote.disconnect(code, &disconnect_message(&result)).await;
+    result?;
+    disconnected?;
     Ok(())
 }
+
+/// One line on what the restore did, for the end of the log.
+fn summary(stats: &RestoreStats, dry_run: bool) -> String {
+    let mut line = format!(
+        "{} {} files ({}), {} links and {} directories in {:.1}s, {}/s",
+        if dry_run { "would write" } else { "wrote" },
+        stats.files,
+        HumanBytes(stats.bytes),
+        stats.links,
+        stats.created_dirs,
+        stats.elapsed.as_secs_f64(),
+        HumanBytes(stats.throughput() as u64),
+    );
+    if stats.skipped > 0 {
+        line += &format!(", skipped {}", stats.skipped);
+    }
+    if stats.deleted > 0 {
+        line += &format!(", deleted {}", stats.deleted);
+    }
+    if !stats.failed.is_empty() {
+        line += &format!(", failed {}", stats.failed.len());
+    }
+    line
+}
+
+/// What the server logs when we hang up after `result`.
+fn disconnect_message(result: &Result<(), Box<dyn std::error::Error>>) -> String {
+    match result {
+        Ok(()) => "goodbye".to_owned(),
+        Err(e) => match e.downcast_ref::<RestoreError>() {
+            Some(RestoreError::Interrupted) => "interrupted".to_owned(),
+            _ => format!("restore failed: {}", e),
+        },
+    }
+}
+
+/// Everything that happens between connecting and disconnecting.
+async fn restore(
+    args: &Args,
+    remote: &RemoteTarget,
+    archives: Vec<Archive<impl futures::AsyncRead + Unpin>>,
+    include: GlobSet,
+    exclude: GlobSet,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chown = match &args.chown {
+        Some(spec) => Some(resolve_owner(remote, spec).await?),
+        None => None,
+    };
+
+    let mut opts = RestoreOptions {
+        base_path: SimplePath::new(
+            args.host
+                .path
+                .clone()
+                .or(args.chdir.clone())
+                .unwrap_or(".".to_owned()),
+        ),
+        strip_components: args.strip_components,
+        absolute_paths: args.absolute_paths,
+        symlinks: args.symlinks,
+        include,
+        exclude,
+        jobs: args.jobs,
+        max_buffered: args.max_buffered,
+        precreate_dirs: Vec::new(),
+        max_file_size: args.max_file_size,
+        oversized: args.oversized,
+        transfer: args.transfer,
+        verify: args.verify,
+        write_checksums: args.write_checksums.as_deref().map(SimplePath::new),
+        retries: args.retries,
+        on_error: args.on_error,
+        io_timeout: args.io_timeout,
+        timeout_per_file: args.timeout_per_file,
+        limit_rate: args.limit_rate,
+        buffer_size: args.buffer_size,
+        preserve_times: !args.no_preserve_times,
+        preserve_permissions: args.preserve_permissions,
+        dir_mode: args.dir_mode,
+        umask: args.umask,
+        chown,
+        preserve_owner: args.preserve_owner,
+        chown_errors: args.chown_errors,
+        temp_dir: args.temp_dir.clone(),
+        clean_tmp: !args.no_clean_tmp,
+        skip_unchanged: args.skip_unchanged,
+        no_clobber: args.no_clobber,
+        resume: args.resume,
+        delete: args.delete,
+        charset: args.remote_charset,
+        pre_command: args.pre_command.clone(),
+        post_command: args.post_command.clone(),
+        post_always: args.post_always,
+        dry_run: args.dry_run,
+        cancel: CancellationToken::new(),
+    };
+    if args.precreate_dirs {
+        // a second read of the files, with every file's contents skipped over
+        let mut dirs = BTreeSet::new();
+        for f in &args.tarfile {
+            let reader = wrap_readable(File::open(f).await?, args.buffer_size);
+            let reader = decompress(reader, args.compression, args.buffer_size).await?;
+            dirs.extend(collect_dirs(Archive::new(reader.compat()), &opts).await?);
+        }
+        opts.precreate_dirs = Vec::from_iter(dirs);
+        opts.precreate_dirs.sort_by_key(SimplePath::depth);
+        debug!("creating {} directories first", opts.precreate_dirs.len());
+    }
+    let cancel = opts.cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    });
+    let stats = restore_archives(remote, archives, &opts).await?;
+    info!("{}", summary(&stats, args.dry_run));
+    #[cfg(feature = "manifest")]
+    if let Some(path) = args.manifest.as_ref() {
+        write_manifest(path, &stats)?;
+    }
+    Ok(stats.check_complete()?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    fn host(login: Option<&str>, host: &str, port: Option<u16>, path: Option<&str>) -> HostSpec {
+        HostSpec {
+            login: login.map(str::to_owned),
+            host: host.to_owned(),
+            port,
+            path: path.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("5M"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5T").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+        assert_eq!(parse_buffer_size("64M"), Ok(64 << 20));
+        assert!(parse_buffer_size("65M").is_err());
+    }
+
+    #[test]
+    fn test_parse_charset() {
+        assert_eq!(parse_charset("latin1"), Ok(encoding_rs::WINDOWS_1252));
+        assert_eq!(parse_charset("Shift_JIS"), Ok(encoding_rs::SHIFT_JIS));
+        assert!(parse_charset("klingon").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0755"), Ok(0o755));
+        assert_eq!(parse_mode("700"), Ok(0o700));
+        assert_eq!(parse_mode("0o2750"), Ok(0o2750));
+        assert!(parse_mode("0789").is_err());
+        assert!(parse_mode("rwx").is_err());
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("+755").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert_eq!(parse_umask("022"), Ok(0o022));
+        assert_eq!(parse_umask("0"), Ok(0));
+        assert!(parse_umask("4022").is_err());
+        assert!(parse_umask("8").is_err());
+    }
+
+    #[test]
+    fn test_parse_env() {
+        let args = Args::try_parse_from([
+            "bakelite-ssh-backend",
+            "--env",
+            "LC_ALL=C",
+            "--env=PATH=/opt/bin:/usr/bin",
+            "--env",
+            "EMPTY=",
+            "backup-server",
+        ])
+        .unwrap();
+        let pair = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+        assert_eq!(
+            args.env,
+            [
+                pair("LC_ALL", "C"),
+                pair("PATH", "/opt/bin:/usr/bin"),
+                pair("EMPTY", "")
+            ]
+        );
+        assert!(parse_env("LC_ALL").is_err());
+        assert!(parse_env("=C").is_err());
+        assert!(parse_env("LC ALL=C").is_err());
+    }
+
+    #[test]
+    fn test_parse_temp_dir() {
+        assert_eq!(parse_temp_dir(".staging/"), Ok(SimplePath::new(".staging")));
+        assert_eq!(parse_temp_dir("/var/tmp"), Ok(SimplePath::new("/var/tmp")));
+        assert!(parse_temp_dir(".").is_err());
+        assert!(parse_temp_dir("a/..").is_err());
+        assert!(parse_temp_dir("a\nb").is_err());
+    }
+
+    #[test]
+    fn test_parse_owner() {
+        let owner = |user, group| Ok(OwnerSpec { user, group });
+        assert_eq!(
+            parse_owner("1000:100"),
+            owner(Some(Id::Number(1000)), Some(Id::Number(100)))
+        );
+        assert_eq!(
+            parse_owner("www-data:staff"),
+            owner(
+                Some(Id::Name("www-data".to_owned())),
+                Some(Id::Name("staff".to_owned()))
+            )
+        );
+        assert_eq!(
+            parse_owner("backup"),
+            owner(Some(Id::Name("backup".to_owned())), None)
+        );
+        assert_eq!(parse_owner("0:"), owner(Some(Id::Number(0)), None));
+        assert_eq!(
+            parse_owner(":wheel"),
+            owner(None, Some(Id::Name("wheel".to_owned())))
+        );
+        assert!(parse_owner("").is_err());
+        assert!(parse_owner(":").is_err());
+        assert!(parse_owner("a:b:c").is_err());
+        assert!(parse_owner("my user").is_err());
+    }
+
+    #[test]
+    fn test_disconnect_message() {
+        assert_eq!(disconnect_message(&Ok(())), "goodbye");
+        assert_eq!(
+            disconnect_message(&Err(RestoreError::Interrupted.into())),
+            "interrupted"
+        );
+        let err = RestoreError::Connect(Error::other("channel closed")).into();
+        assert!(disconnect_message(&Err(err)).starts_with("restore failed: "));
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let code = |e: RestoreError| exit_code(&e);
+        assert_eq!(code(RestoreError::Connect(Error::other("refused"))), 3);
+        assert_eq!(code(RestoreError::Auth(Error::other("denied"))), 4);
+        assert_eq!(
+            code(RestoreError::Transfer {
+                path: SimplePath::new("a"),
+                expected: 2,
+                wrote: 1,
+            }),
+            5
+        );
+        assert_eq!(code(RestoreError::Incomplete { failed: 2 }), 5);
+        assert_eq!(code(RestoreError::Interrupted), 6);
+        assert_eq!(
+            code(RestoreError::OutOfSpace {
+                path: SimplePath::new("a"),
+            }),
+            7
+        );
+        assert_eq!(
+            code(RestoreError::Hook {
+                command: "false".to_owned(),
+                source: Error::other("exited with status 1"),
+            }),
+            8
+        );
+        assert_eq!(code(RestoreError::Archive(Error::other("corrupt"))), 1);
+        assert_eq!(exit_code(&Error::other("no such file")), 1);
+    }
+
+    #[test]
+    fn test_parse_jump() {
+        assert_eq!(
+            parse_jump("admin@bastion:2200"),
+            Ok(host(Some("admin"), "bastion", Some(2200), None))
+        );
+        assert!(parse_jump("bastion:/srv").is_err());
+        assert!(parse_jump("one,two").is_err());
+    }
+
+    #[test]
+    fn test_resolve_hop() {
+        let resolve = |argv: &[&str]| {
+            let args = Args::try_parse_from(
+                ["bakelite-ssh-backend", "-F", "tests/fixtures/ssh_config"]
+                    .iter()
+                    .chain(argv),
+            )
+            .unwrap();
+            resolve_hop(&args.host, args.login.as_deref(), args.port, &args).unwrap()
+        };
+        let hop = |login: &str, host: &str, port: u16, proxy_jump: Option<&str>| Hop {
+            login: login.to_owned(),
+            host: host.to_owned(),
+            port,
+            identities: Vec::new(),
+            proxy_jump: proxy_jump.map(str::to_owned),
+        };
+        assert_eq!(
+            resolve(&["backup-server"]),
+            hop("restore", "backup.example.com", 2222, None)
+        );
+        assert_eq!(
+            resolve(&["-l", "me", "-p", "2000", "backup-server"]),
+            hop("me", "backup.example.com", 2000, None)
+        );
+        assert_eq!(
+            resolve(&["-p", "2000", "other@backup-server:2001"]),
+            hop("other", "backup.example.com", 2001, None)
+        );
+        assert_eq!(
+            resolve(&["web1.internal"]),
+            hop("ops", "web1.internal", 22, Some("bastion.internal"))
+        );
+    }
+
+    #[test]
+    fn test_parse_host() {
+        assert_eq!(parse_host("server"), Ok(host(None, "server", None, None)));
+        assert_eq!(
+            parse_host("backup@server"),
+            Ok(host(Some("backup"), "server", None, None))
+        );
+        assert_eq!(
+            parse_host("backup@server:2222"),
+            Ok(host(Some("backup"), "server", Some(2222), None))
+        );
+        assert_eq!(
+            parse_host("backup@server:/srv/backups"),
+            Ok(host(Some("backup"), "server", None, Some("/srv/backups")))
+        );
+        assert_eq!(
+            parse_host("server:2222:/srv/backups"),
+            Ok(host(None, "server", Some(2222), Some("/srv/backups")))
+        );
+        assert_eq!(
+            parse_host("server:backups"),
+            Ok(host(None, "server", None, Some("backups")))
+        );
+        assert_eq!(parse_host("server:"), Ok(host(None, "server", None, None)));
+        assert_eq!(
+            parse_host("root@[::1]:22"),
+            Ok(host(Some("root"), "::1", Some(22), None))
+        );
+        assert_eq!(
+            parse_host("[2001:db8::1]:/srv"),
+            Ok(host(None, "2001:db8::1", None, Some("/srv")))
+        );
+        assert_eq!(parse_host("[::1]"), Ok(host(None, "::1", None, None)));
+        assert_eq!(parse_host("::1"), Ok(host(None, "::1", None, None)));
+        assert_eq!(
+            parse_host("root@2001:db8::1"),
+            Ok(host(Some("root"), "2001:db8::1", None, None))
+        );
+        assert!(parse_host("[::1").is_err());
+        assert!(parse_host("[::1]x").is_err());
+        assert!(parse_host("user@").is_err());
+        assert!(parse_host("server:99999").is_err());
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert!(parse_url("https://example.com/backup.tar.zst").is_ok());
+        assert!(parse_url("http://[::1]:8080/a.tar").is_ok());
+        assert!(parse_url("ftp://example.com/a.tar").is_err());
+        assert!(parse_url("backup.tar").is_err());
+    }
+
+    async fn serve_http(responses: Vec<String>) -> Url {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            for resp in responses {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = sock.read(&mut buf).await.unwrap();
+                sock.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_open_url() {
+        use tokio::io::AsyncReadExt;
+
+        let url = serve_http(vec![
+            "HTTP/1.1 302 Found\r\nlocation: /a.tar\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_owned(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello".to_owned(),
+        ])
+        .await;
+        let (mut body, len) = open_url(&url).await.unwrap();
+        let mut data = Vec::new();
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(len, Some(5));
+        assert_eq!(data, b"hello");
+
+        let url = serve_http(vec![
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_owned(),
+        ])
+        .await;
+        let err = open_url(&url).await.err().unwrap();
+        assert!(err.to_string().contains("404 Not Found"), "{}", err);
+    }
+
+    #[test]
+    fn test_no_clobber_conflicts() {
+        let parse = |argv: &[&str]| {
+            Args::try_parse_from(["bakelite-ssh-backend", "backup-server"].iter().chain(argv))
+        };
+        assert!(parse(&["--no-clobber"]).unwrap().no_clobber);
+        let err = parse(&["--no-clobber", "--skip-unchanged"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_jobs() {
+        let parse = |argv: &[&str]| {
+            Args::try_parse_from(["bakelite-ssh-backend", "backup-server"].iter().chain(argv))
+        };
+        assert_eq!(parse(&[]).unwrap().jobs.get(), 4);
+        assert_eq!(parse(&["-j", "8"]).unwrap().jobs.get(), 8);
+        // zero would mean no limit at all to try_for_each_concurrent
+        assert!(parse(&["--jobs", "0"]).is_err());
+        assert_eq!(parse(&[]).unwrap().max_buffered, 16 << 20);
+        assert_eq!(
+            parse(&["--max-buffered", "1M"]).unwrap().max_buffered,
+            1 << 20
+        );
+    }
+
+    #[test]
+    fn test_parse_algorithms() {
+        assert_eq!(
+            parse_cipher("aes256-ctr,aes128-ctr"),
+            Ok("aes256-ctr,aes128-ctr".to_owned())
+        );
+        assert_eq!(parse_mac("hmac-sha2-256"), Ok("hmac-sha2-256".to_owned()));
+        assert!(parse_cipher("aes256-ctr,rot13").is_err());
+        assert!(parse_cipher("hmac-sha2-256").is_err());
+        assert!(parse_mac("aes256-ctr").is_err());
+        assert!(parse_mac("").is_err());
+        let parsed =
+            Args::try_parse_from(["bakelite-ssh-backend", "--cipher", "rot13", "backup-server"]);
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn test_explain_negotiation() {
+        let kex = Error::other("Unable to exchange encryption keys");
+        assert!(explain_negotiation(kex)
+            .to_string()
+            .starts_with("no algorithms in common"));
+        let other = Error::other("Failed getting banner");
+        assert_eq!(
+            explain_negotiation(other).to_string(),
+            "Failed getting banner"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ssh_compression() {
+        let parse = |argv: &[&str]| {
+            Args::try_parse_from(["bakelite-ssh-backend", "backup-server"].iter().chain(argv))
+                .map(|args| args.ssh_compression)
+        };
+        assert_eq!(parse(&[]).unwrap(), SshCompression::Off);
+        assert_eq!(
+            parse(&["--ssh-compression", "on"]).unwrap(),
+            SshCompression::On
+        );
+        assert!(parse(&["--ssh-compression", "zlib"]).is_err());
+
+        // libssh2 refuses a preference without one method it knows
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut config = SessionConfiguration::new();
+        config.set_compress(true);
+        let session = AsyncSession::new(Async::new(sock).unwrap(), Some(config)).unwrap();
+        for compression in [SshCompression::On, SshCompression::Off] {
+            set_compression(&session, compression).await.unwrap();
+        }
+        assert!(session
+            .method_pref(MethodType::CompCs, "lz4")
+            .await
+            .is_err());
+        // libssh2 owns the socket as well and would close it a second time
+        std::mem::forget(session);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp() {
+        let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let sock = connect_tcp("::1", port, false, false).await.unwrap();
+        assert!(sock.peer_addr().unwrap().is_ipv6());
+        let sock = connect_tcp("::1", port, false, true).await.unwrap();
+        assert!(sock.peer_addr().unwrap().is_ipv6());
+        let err = connect_tcp("::1", port, true, false).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[cfg(feature = "manifest")]
+    #[test]
+    fn test_write_manifest() {
+        let path = std::env::temp_dir().join(format!("manifest-{}.json", std::process::id()));
+        let stats = RestoreStats {
+            dirs: 1,
+            created_dirs: 1,
+            files: 1,
+            links: 0,
+            bytes: 5,
+            skipped: 0,
+            deleted: 0,
+            uploaded: vec![bakelite_ssh_backend::UploadedFile {
+                path: SimplePath::new("/srv/a"),
+                size: 5,
+                mode: 0o644,
+                mtime: 1_600_000_000,
+            }],
+            failed: Vec::new(),
+            elapsed: Duration::from_millis(1500),
+        };
+        write_manifest(&path, &stats).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(json["files"], 1);
+        assert_eq!(json["bytes"], 5);
+        assert_eq!(json["elapsed_secs"], 1.5);
+        assert_eq!(json["uploaded"][0]["path"], "/srv/a");
+        assert_eq!(json["uploaded"][0]["mode"], 0o644);
+        assert_eq!(json["uploaded"][0]["mtime"], 1_600_000_000);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut stats = RestoreStats {
+            dirs: 2,
+            created_dirs: 3,
+            files: 4,
+            links: 1,
+            bytes: 3 << 20,
+            elapsed: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            summary(&stats, false),
+            "wrote 4 files (3.00 MiB), 1 links and 3 directories in 2.0s, 1.50 MiB/s"
+        );
+        stats.skipped = 5;
+        stats.deleted = 6;
+        stats.failed.push(bakelite_ssh_backend::FailedEntry {
+            path: SimplePath::new("a"),
+            error: "a: permission denied".to_owned(),
+        });
+        assert_eq!(
+            summary(&stats, true),
+            "would write 4 files (3.00 MiB), 1 links and 3 directories in 2.0s, 1.50 MiB/s, \
+             skipped 5, deleted 6, failed 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_in_order() {
+        let keys = ["bad".to_owned(), "good".to_owned(), "unused".to_owned()];
+        let mut tried = Vec::new();
+        let result = try_in_order(&keys, |key| {
+            tried.push(key.as_str());
+            let result = match key.as_str() {
+                "good" => Ok(()),
+                _ => Err(Error::other(format!("{} was rejected", key))),
+            };
+            async { result }
+        })
+        .await;
+        assert!(matches!(result, Some(Ok(()))));
+        assert_eq!(tried, ["bad", "good"]);
+
+        let result = try_in_order(&keys[..1], |key| {
+            let e = Error::other(format!("{} was rejected", key));
+            async { Err(e) }
+        })
+        .await;
+        assert_eq!(result.unwrap().unwrap_err().to_string(), "bad was rejected");
+        assert!(try_in_order(&[] as &[String], |_| async { Ok(()) })
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let err = identity_file("/nonexistent/id_ed25519").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "identity file /nonexistent/id_ed25519 does not exist"
+        );
+        assert!(identity_file("/").is_err());
+        assert!(identity_file("Cargo.toml").is_ok());
+    }
+}
@@ -0,0 +1,3871 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_ssh2_lite::{
+    ssh2::{DisconnectCode, ExtendedData, FileStat, OpenFlags, OpenType},
+    AsyncChannel, AsyncFile, AsyncSession, AsyncSftp,
+};
+use async_tar::Archive;
+use clap::ValueEnum;
+use encoding_rs::Encoding;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{PathError, PathStr, SimplePath};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verify {
+    Sha256,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    Scp,
+    Sftp,
+}
+
+/// Where entries with an absolute path in the archive go.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbsolutePaths {
+    /// Write them to that path on the remote, outside the destination if need be
+    Keep,
+    /// Drop the leading `/` and restore them under the destination
+    Reroot,
+    /// Stop the restore with an error
+    Reject,
+}
+
+/// What to do with symlink and hard link entries, both of which are restored as symlinks.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Create a symlink pointing where the archive's does
+    Recreate,
+    /// Leave them out
+    Skip,
+    /// Write a copy of the file they point to instead. Only a file in the archive can be
+    /// copied, so links to directories or to anything the archive doesn't have are skipped
+    Follow,
+}
+
+/// What to do when an entry can't be written to the server.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop the restore
+    Abort,
+    /// Log the failure, record it in [`RestoreStats::failed`] and go on with the next entry
+    Continue,
+}
+
+/// What to do with a file larger than [`RestoreOptions::max_file_size`].
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Oversized {
+    Skip,
+    Fail,
+}
+
+/// What to do when the server refuses to change an owner.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChownErrors {
+    Warn,
+    Fail,
+}
+
+impl ChownErrors {
+    fn handle(self, dst: &SimplePath, result: Result<(), Error>) -> Result<(), RestoreError> {
+        match (result, self) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), ChownErrors::Warn) => {
+                warn!("could not change the owner of {}: {}", dst, e);
+                Ok(())
+            }
+            (Err(e), ChownErrors::Fail) => Err(RestoreError::sftp(dst)(e)),
+        }
+    }
+}
+
+/// Numeric ids to give restored files, either one left as `None` is kept as the server chose.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// Fills in whatever `self` leaves unset from `other`.
+    pub fn or(self, other: Owner) -> Owner {
+        Owner {
+            uid: self.uid.or(other.uid),
+            gid: self.gid.or(other.gid),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none()
+    }
+}
+
+/// Where in-flight uploads go unless [`RestoreOptions::temp_dir`] says otherwise.
+pub const DEFAULT_TEMP_DIR: &str = ".tmp";
+
+/// Settings for [`restore_archive`], the defaults match the command line.
+#[derive(Clone, Debug)]
+pub struct RestoreOptions {
+    /// The remote directory entries are restored under
+    pub base_path: SimplePath,
+    /// Leading components to strip from entry paths, shorter entries are skipped
+    pub strip_components: usize,
+    pub absolute_paths: AbsolutePaths,
+    pub symlinks: Symlinks,
+    /// Only restore entries matching one of these, unless it is empty
+    pub include: GlobSet,
+    /// Skip entries matching one of these, even if included
+    pub exclude: GlobSet,
+    /// The number of files to upload in parallel
+    pub jobs: NonZeroUsize,
+    /// Files up to this size are read into memory, so they can go in parallel and a failed
+    /// upload can be retried. Bigger ones are streamed from the archive in a single attempt
+    pub max_buffered: u64,
+    /// Directories to create before any entry is written, usually from [`collect_dirs`]
+    pub precreate_dirs: Vec<SimplePath>,
+    /// The largest size an entry's header may declare
+    pub max_file_size: Option<u64>,
+    pub oversized: Oversized,
+    pub transfer: Transfer,
+    pub verify: Option<Verify>,
+    /// Upload a `sha256sum` style list of every file to this path, taken relative to
+    /// `base_path` like the paths it lists
+    pub write_checksums: Option<SimplePath>,
+    /// How many times to retry an upload after a transient error
+    pub retries: u32,
+    /// Whether an entry that still fails once retries are used up stops the restore. Only
+    /// failures on the server apply, the archive can't be read past a broken entry
+    pub on_error: OnError,
+    /// Seconds before a stalled write fails
+    pub io_timeout: Option<u64>,
+    /// Seconds any one upload may take however it is progressing, each retry starting afresh
+    pub timeout_per_file: Option<u64>,
+    /// Bytes per second shared by all uploads
+    pub limit_rate: Option<u64>,
+    /// The most bytes handed to a single write on the channel
+    pub buffer_size: usize,
+    pub preserve_times: bool,
+    pub preserve_permissions: bool,
+    /// The mode to create missing directories with
+    pub dir_mode: u32,
+    /// Bits cleared from every file and directory mode, after everything else
+    pub umask: u32,
+    /// Give every file and directory written to this owner, over `preserve_owner`
+    pub chown: Option<Owner>,
+    /// Apply the uid and gid recorded in the archive
+    pub preserve_owner: bool,
+    pub chown_errors: ChownErrors,
+    /// Where files are written before being renamed into place, under `base_path` unless
+    /// absolute. Renames can't cross filesystems, so it must be on the same one as every file
+    pub temp_dir: SimplePath,
+    /// Remove files left in the temp directory by an interrupted run
+    pub clean_tmp: bool,
+    /// Leave remote files alone when they already match the archive
+    pub skip_unchanged: bool,
+    /// Never write over a file or link already on the remote, whatever it holds
+    pub no_clobber: bool,
+    /// Leave remote files alone when they already have the archive's size, as every upload left
+    /// by an interrupted run does
+    pub resume: bool,
+    /// Remove anything under `base_path` the archive doesn't have, sparing excluded paths
+    pub delete: bool,
+    /// The encoding of file names in the archive, lossy UTF-8 when unset
+    pub charset: Option<&'static Encoding>,
+    /// A shell command run on the remote before anything is written, the restore fails if it does
+    pub pre_command: Option<String>,
+    /// A shell command run on the remote once the restore succeeds
+    pub post_command: Option<String>,
+    /// Run `post_command` after a failed restore too
+    pub post_always: bool,
+    /// Only print what would be written
+    pub dry_run: bool,
+    /// Stops the restore early with [`RestoreError::Interrupted`] once cancelled
+    pub cancel: CancellationToken,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            base_path: SimplePath::new("."),
+            strip_components: 0,
+            absolute_paths: AbsolutePaths::Reroot,
+            symlinks: Symlinks::Recreate,
+            include: GlobSet::empty(),
+            exclude: GlobSet::empty(),
+            jobs: NonZeroUsize::new(4).unwrap(),
+            max_buffered: 16 * 1024 * 1024,
+            precreate_dirs: Vec::new(),
+            max_file_size: None,
+            oversized: Oversized::Skip,
+            transfer: Transfer::Scp,
+            verify: None,
+            write_checksums: None,
+            retries: 3,
+            on_error: OnError::Abort,
+            io_timeout: None,
+            timeout_per_file: None,
+            limit_rate: None,
+            buffer_size: 256 * 1024,
+            preserve_times: true,
+            preserve_permissions: false,
+            dir_mode: 0o755,
+            umask: 0,
+            chown: None,
+            preserve_owner: false,
+            chown_errors: ChownErrors::Warn,
+            temp_dir: SimplePath::new(DEFAULT_TEMP_DIR),
+            clean_tmp: true,
+            skip_unchanged: false,
+            no_clobber: false,
+            resume: false,
+            delete: false,
+            charset: None,
+            pre_command: None,
+            post_command: None,
+            post_always: false,
+            dry_run: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+impl RestoreOptions {
+    /// The mode a file recorded in the archive with `mode` is written with.
+    fn file_mode(&self, mode: u32) -> u32 {
+        let mode = if self.preserve_permissions {
+            mode
+        } else {
+            0o644
+        };
+        mode & !self.umask
+    }
+
+    /// The mode a directory recorded in the archive with `mode` is left with.
+    fn recorded_dir_mode(&self, mode: u32) -> u32 {
+        mode & !self.umask
+    }
+
+    /// Where a remote path given relative to the base is.
+    fn under_base(&self, pth: &SimplePath) -> SimplePath {
+        if pth.is_absolute() {
+            pth.normalize()
+        } else {
+            self.base_path.join_normalized(pth)
+        }
+    }
+
+    fn checksums_path(&self) -> Option<SimplePath> {
+        self.write_checksums
+            .as_ref()
+            .map(|pth| self.under_base(pth))
+    }
+
+    fn tmp_path(&self) -> SimplePath {
+        self.under_base(&self.temp_dir)
+    }
+
+    /// The mode missing directories are created with.
+    fn created_dir_mode(&self) -> u32 {
+        self.dir_mode & !self.umask
+    }
+
+    /// Whether files are hashed as they are read, for `verify` or the manifest.
+    fn takes_checksums(&self) -> bool {
+        self.verify == Some(Verify::Sha256) || self.write_checksums.is_some()
+    }
+}
+
+#[derive(Debug)]
+pub enum RestoreError {
+    Connect(Error),
+    Auth(Error),
+    Sftp {
+        path: SimplePath,
+        source: Error,
+    },
+    Transfer {
+        path: SimplePath,
+        expected: u64,
+        wrote: u64,
+    },
+    Checksum {
+        path: SimplePath,
+        expected: String,
+        actual: String,
+    },
+    OutOfSpace {
+        path: SimplePath,
+    },
+    Archive(Error),
+    Path(PathError),
+    Interrupted,
+    /// Some entries failed with [`OnError::Continue`] and the rest were restored
+    Incomplete {
+        failed: usize,
+    },
+    /// [`RestoreOptions::pre_command`] or [`RestoreOptions::post_command`] failed
+    Hook {
+        command: String,
+        source: Error,
+    },
+}
+
+impl RestoreError {
+    fn sftp(path: &SimplePath) -> impl Fn(Error) -> Self + '_ {
+        move |source| {
+            if is_out_of_space(&source) {
+                RestoreError::OutOfSpace { path: path.clone() }
+            } else {
+                RestoreError::Sftp {
+                    path: path.clone(),
+                    source,
+                }
+            }
+        }
+    }
+
+    /// Whether retrying the same operation could succeed, like after a dropped connection.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RestoreError::Sftp { source, .. } => matches!(
+                source.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether this failure leaves the rest of the restore unaffected, as when the server
+    /// rejects one file.
+    fn is_entry_failure(&self) -> bool {
+        matches!(
+            self,
+            RestoreError::Sftp { .. }
+                | RestoreError::Transfer { .. }
+                | RestoreError::Checksum { .. }
+        )
+    }
+}
+
+/// libssh2 keeps the SFTP status for a full disk only in the message, and OpenSSH's sftp-server
+/// reports one as a bare failure, so this catches what it can.
+fn is_out_of_space(e: &Error) -> bool {
+    if matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded) {
+        return true;
+    }
+    let msg = e.to_string().to_ascii_lowercase();
+    [
+        "no space on filesystem",
+        "no space left on device",
+        "quota exceeded",
+    ]
+    .iter()
+    .any(|m| msg.contains(m))
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::Connect(e) => write!(f, "could not connect: {}", e),
+            RestoreError::Auth(e) => write!(f, "could not authenticate: {}", e),
+            RestoreError::Sftp { path, source } => write!(f, "{}: {}", path, source),
+            RestoreError::Transfer {
+                path,
+                expected,
+                wrote,
+            } => write!(
+                f,
+                "{}: expected {} bytes but only wrote {}",
+                path, expected, wrote
+            ),
+            RestoreError::Checksum {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "checksum mismatch for {}: expected {} but remote has {}",
+                path, expected, actual
+            ),
+            RestoreError::OutOfSpace { path } => {
+                write!(f, "{}: the server is out of disk space or quota", path)
+            }
+            RestoreError::Archive(e) => write!(f, "could not read the archive: {}", e),
+            RestoreError::Path(e) => e.fmt(f),
+            RestoreError::Interrupted => write!(f, "interrupted"),
+            RestoreError::Incomplete { failed: 1 } => write!(f, "1 entry could not be restored"),
+            RestoreError::Incomplete { failed } => {
+                write!(f, "{} entries could not be restored", failed)
+            }
+            RestoreError::Hook { command, source } => write!(f, "{}: {}", command, source),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RestoreError::Connect(e) | RestoreError::Auth(e) | RestoreError::Archive(e) => Some(e),
+            RestoreError::Sftp { source, .. } | RestoreError::Hook { source, .. } => Some(source),
+            RestoreError::Path(e) => Some(e),
+            RestoreError::Transfer { .. }
+            | RestoreError::Checksum { .. }
+            | RestoreError::OutOfSpace { .. }
+            | RestoreError::Interrupted
+            | RestoreError::Incomplete { .. } => None,
+        }
+    }
+}
+
+impl From<PathError> for RestoreError {
+    fn from(e: PathError) -> Self {
+        RestoreError::Path(e)
+    }
+}
+
+/// A file written by [`restore_archive`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UploadedFile {
+    pub path: SimplePath,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+/// An entry [`restore_archive`] gave up on with [`OnError::Continue`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FailedEntry {
+    pub path: SimplePath,
+    pub error: String,
+}
+
+/// What [`restore_archive`] wrote, or would have written in a dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RestoreStats {
+    /// Directory entries in the archive
+    pub dirs: usize,
+    /// Directories that were missing on the remote and had to be made
+    pub created_dirs: usize,
+    pub files: usize,
+    pub links: usize,
+    pub bytes: u64,
+    /// Files left alone because the remote copy already matched or, resuming, was complete
+    pub skipped: usize,
+    /// Remote files and directories removed because the archive doesn't have them
+    pub deleted: usize,
+    /// Sorted by path
+    pub uploaded: Vec<UploadedFile>,
+    /// Sorted by path
+    pub failed: Vec<FailedEntry>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_secs", serialize_with = "serialize_secs")
+    )]
+    pub elapsed: Duration,
+}
+
+impl RestoreStats {
+    /// The average upload rate in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Fails with [`RestoreError::Incomplete`] if any entry could not be restored, which only
+    /// happens with [`OnError::Continue`].
+    pub fn check_complete(&self) -> Result<(), RestoreError> {
+        match self.failed.len() {
+            0 => Ok(()),
+            failed => Err(RestoreError::Incomplete { failed }),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_secs<S: serde::Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(d.as_secs_f64())
+}
+
+/// Counts what the concurrent uploads write, for [`RestoreStats`].
+#[derive(Default)]
+struct Tally {
+    dirs: AtomicUsize,
+    links: AtomicUsize,
+    skipped: AtomicUsize,
+    bytes: AtomicU64,
+    uploaded: Mutex<Vec<UploadedFile>>,
+    failed: Mutex<Vec<FailedEntry>>,
+}
+
+impl Tally {
+    fn dir(&self) {
+        self.dirs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn link(&self) {
+        self.links.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn skip(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn file(&self, upload: &Upload) {
+        self.bytes.fetch_add(upload.size, Ordering::Relaxed);
+        self.uploaded.lock().unwrap().push(UploadedFile {
+            path: upload.dst.clone(),
+            size: upload.size,
+            mode: upload.mode as u32,
+            mtime: upload.mtime,
+        });
+    }
+
+    /// Passes `result` on unless `on_error` says to go on past it, in which case the failure is
+    /// logged and kept for the stats instead.
+    fn settle(
+        &self,
+        dst: &SimplePath,
+        result: Result<(), RestoreError>,
+        on_error: OnError,
+    ) -> Result<(), RestoreError> {
+        match result {
+            Err(e) if on_error == OnError::Continue && e.is_entry_failure() => {
+                error!("{}", e);
+                self.failed.lock().unwrap().push(FailedEntry {
+                    path: dst.clone(),
+                    error: e.to_string(),
+                });
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn into_stats(self, created_dirs: usize, deleted: usize, elapsed: Duration) -> RestoreStats {
+        let uploaded = keep_latest(self.uploaded.into_inner().unwrap(), |file| &file.path);
+        let mut failed = self.failed.into_inner().unwrap();
+        failed.sort_by(|a, b| a.path.cmp(&b.path));
+        RestoreStats {
+            dirs: self.dirs.into_inner(),
+            created_dirs,
+            files: uploaded.len(),
+            links: self.links.into_inner(),
+            bytes: self.bytes.into_inner(),
+            skipped: self.skipped.into_inner(),
+            deleted,
+            uploaded,
+            failed,
+            elapsed,
+        }
+    }
+}
+
+pub fn build_globset(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// Whether to restore `pth`: everything is included unless `--include` was given, and
+/// excludes are applied after includes.
+fn is_selected(pth: &SimplePath, include: &GlobSet, exclude: &GlobSet) -> bool {
+    (include.is_empty() || include.is_match(pth.as_str())) && !exclude.is_match(pth.as_str())
+}
+
+struct Upload {
+    dst: SimplePath,
+    size: u64,
+    mode: i32,
+    mtime: u64,
+    owner: Owner,
+    /// `None` when the contents are read from elsewhere, like the archive for a file too big to
+    /// buffer
+    data: Option<Vec<u8>>,
+    sha256: Option<String>,
+}
+
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn hex_digest(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.hasher.update(&buf[..n]);
+        }
+        poll
+    }
+}
+
+enum Entry {
+    Dir {
+        dst: SimplePath,
+        mode: u32,
+        owner: Owner,
+    },
+    File(Upload),
+    Symlink {
+        dst: SimplePath,
+        target: SimplePath,
+    },
+    /// Left unwritten by an option, but still in the archive, so `--delete` spares `dst`.
+    Skipped {
+        dst: SimplePath,
+    },
+}
+
+impl Entry {
+    fn dst(&self) -> &SimplePath {
+        match self {
+            Entry::Dir { dst, .. } => dst,
+            Entry::File(upload) => &upload.dst,
+            Entry::Symlink { dst, .. } => dst,
+            Entry::Skipped { dst } => dst,
+        }
+    }
+}
+
+fn link_target(
+    base_path: &SimplePath,
+    dst: &SimplePath,
+    target: &str,
+    hard: bool,
+) -> Result<SimplePath, PathError> {
+    let base_path = base_path.normalize();
+    let parent = dst.parent().unwrap_or_default();
+    let target_path = SimplePath::new(target);
+    // hard links and absolute symlinks name a path in the archive, so put them under the base
+    let resolved = if hard {
+        base_path.join(SimplePath::new_safe(target)?)
+    } else if target_path.is_absolute() {
+        base_path.join(target_path.strip_prefix("/").unwrap_or_default())
+    } else {
+        parent.join(&target_path)
+    }
+    .normalize();
+    match resolved.strip_prefix(&base_path) {
+        Some(rest) if !rest.starts_with("..") => resolved
+            .relative_to(&parent)
+            .ok_or_else(|| PathError::Traversal(target.to_owned())),
+        _ => Err(PathError::Traversal(target.to_owned())),
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Opens a channel to run a command on, with the environment of `remote` set.
+async fn command_channel(
+    remote: &RemoteTarget,
+) -> Result<AsyncChannel<std::net::TcpStream>, std::io::Error> {
+    let mut ch = remote.session().channel_session().await?;
+    for (key, value) in remote.env.iter() {
+        // servers only take what their AcceptEnv allows, the command may not need it anyway
+        if let Err(e) = ch.setenv(key, value).await {
+            if remote.refused_env.lock().unwrap().insert(key.clone()) {
+                warn!(
+                    "server refused to set {}, running commands without it: {}",
+                    key, e
+                );
+            }
+        }
+    }
+    Ok(ch)
+}
+
+/// Runs `command` on the remote and returns what it printed, failing unless it exits 0.
+async fn remote_output(remote: &RemoteTarget, command: &str) -> Result<String, std::io::Error> {
+    let mut ch = command_channel(remote).await?;
+    ch.exec(command).await?;
+    let mut out = String::new();
+    ch.read_to_string(&mut out).await?;
+    ch.wait_close().await?;
+    match ch.exit_status()? {
+        0 => Ok(out),
+        status => Err(Error::other(format!(
+            "{} exited with status {}",
+            command, status
+        ))),
+    }
+}
+
+/// Runs a hook through the remote's shell, logging everything it prints to stdout or stderr.
+async fn run_hook(remote: &RemoteTarget, command: String) -> Result<(), RestoreError> {
+    let result = async {
+        let mut ch = command_channel(remote).await?;
+        ch.handle_extended_data(ExtendedData::Merge).await?;
+        ch.exec(&command).await?;
+        let mut out = String::new();
+        ch.read_to_string(&mut out).await?;
+        ch.wait_close().await?;
+        for line in out.lines() {
+            info!("{}: {}", command, line);
+        }
+        let status = ch.exit_status()?;
+        info!("ran {} [exit status {}]", command, status);
+        match status {
+            0 => Ok(()),
+            status => Err(Error::other(format!("exited with status {}", status))),
+        }
+    }
+    .await;
+    result.map_err(|source| RestoreError::Hook { command, source })
+}
+
+/// Runs `restore` between [`RestoreOptions::pre_command`] and [`RestoreOptions::post_command`],
+/// using `hook` to run each.
+async fn with_hooks<T, H, HF>(
+    opts: &RestoreOptions,
+    mut hook: H,
+    restore: impl Future<Output = Result<T, RestoreError>>,
+) -> Result<T, RestoreError>
+where
+    H: FnMut(String) -> HF,
+    HF: Future<Output = Result<(), RestoreError>>,
+{
+    let mut run = |command: String| {
+        let ran = (!opts.dry_run).then(|| hook(command.clone()));
+        async move {
+            match ran {
+                Some(ran) => ran.await,
+                None => {
+                    info!("would run {}", command);
+                    Ok(())
+                }
+            }
+        }
+    };
+    if let Some(command) = &opts.pre_command {
+        // whatever it was meant to stop may still be running, so nothing is written
+        run(command.clone()).await?;
+    }
+    let result = restore.await;
+    match &opts.post_command {
+        Some(command) if result.is_ok() || opts.post_always => {
+            match (result, run(command.clone()).await) {
+                (Ok(stats), ran) => ran.map(|()| stats),
+                (Err(e), ran) => {
+                    // the restore failing is what matters, the hook is only logged
+                    if let Err(hook_err) = ran {
+                        error!("{}", hook_err);
+                    }
+                    Err(e)
+                }
+            }
+        }
+        _ => result,
+    }
+}
+
+async fn remote_sha256(remote: &impl Remote, pth: &SimplePath) -> Result<String, std::io::Error> {
+    let out = remote
+        .output(&format!("sha256sum -- {}", shell_quote(pth.as_str())))
+        .await?;
+    Ok(out.split_whitespace().next().unwrap_or_default().to_owned())
+}
+
+/// Whether `a` and `b` are on the same filesystem of the remote, going by `stat`. SFTP itself
+/// has no way to tell.
+async fn same_filesystem(
+    remote: &impl Remote,
+    a: &SimplePath,
+    b: &SimplePath,
+) -> Result<bool, std::io::Error> {
+    let command = format!(
+        "stat -c %d -- {} {}",
+        shell_quote(a.as_str()),
+        shell_quote(b.as_str())
+    );
+    let out = remote.output(&command).await?;
+    let devices: Vec<_> = out.split_whitespace().collect();
+    match devices[..] {
+        [a, b] => Ok(a == b),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} printed {:?}", command, out),
+        )),
+    }
+}
+
+/// Looks up the numeric id of a user, or of a group when `group` is set, on the remote.
+pub async fn remote_id(
+    remote: &RemoteTarget,
+    name: &str,
+    group: bool,
+) -> Result<u32, std::io::Error> {
+    let command = if group {
+        format!("getent group {}", shell_quote(name))
+    } else {
+        format!("id -u {}", shell_quote(name))
+    };
+    let out = remote_output(remote, &command).await?;
+    // `getent group` prints name:password:gid:members
+    let id = if group {
+        out.split(':').nth(2)
+    } else {
+        Some(out.as_str())
+    };
+    id.and_then(|id| id.trim().parse().ok()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} printed no id for {}", command, name),
+        )
+    })
+}
+
+/// Changes the owner of `pth`, asking for whichever id `owner` doesn't set since the
+/// server only takes both at once.
+async fn set_owner(remote: &impl Remote, pth: &SimplePath, owner: Owner) -> Result<(), Error> {
+    let (uid, gid) = match owner {
+        Owner {
+            uid: Some(uid),
+            gid: Some(gid),
+        } => (uid, gid),
+        _ => {
+            let stat = remote.stat(pth).await?;
+            match (owner.uid.or(stat.uid), owner.gid.or(stat.gid)) {
+                (Some(uid), Some(gid)) => (uid, gid),
+                _ => return Err(Error::other("the server did not report the current owner")),
+            }
+        }
+    };
+    let stat = FileStat {
+        size: None,
+        uid: Some(uid),
+        gid: Some(gid),
+        perm: None,
+        atime: None,
+        mtime: None,
+    };
+    remote.setstat(pth, stat).await
+}
+
+pub async fn with_timeout<T>(
+    secs: Option<u64>,
+    what: &str,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match secs {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), fut)
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} timed out after {}s", what, secs),
+                ))
+            }),
+        None => fut.await,
+    }
+}
+
+/// A token bucket shared by every upload, so concurrent jobs split one budget.
+struct RateLimiter {
+    rate: f64,
+    state: tokio::sync::Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: bytes_per_sec as f64,
+            state: tokio::sync::Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self, n: usize) {
+        // held while sleeping so waiters queue up instead of all waking at once
+        let mut state = self.state.lock().await;
+        let (available, last) = *state;
+        let now = Instant::now();
+        // at most a second of unused budget carries over
+        let available = (available + now.duration_since(last).as_secs_f64() * self.rate)
+            .min(self.rate)
+            - n as f64;
+        *state = (available, now);
+        if available < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-available / self.rate)).await;
+        }
+    }
+}
+
+/// Copies `reader` to `writer` in `buffer_size` slices. Large writes let libssh2 keep
+/// several SFTP packets in flight instead of waiting out a round trip per
+/// 8 KiB. A failed write fails the copy, while a failed read is handed back
+/// inside it, since the file being copied is to blame rather than the server.
+async fn copy_chunked<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    writer: &mut W,
+    buffer_size: usize,
+    limiter: Option<&RateLimiter>,
+) -> Result<Result<u64, Error>, Error> {
+    let mut buf = vec![0; buffer_size.max(1)];
+    let mut written = 0;
+    loop {
+        // an archive entry comes out a block at a time, so reads are gathered into full slices
+        let mut len = 0;
+        while len < buf.len() {
+            match reader.read(&mut buf[len..]).await {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        if len == 0 {
+            return Ok(Ok(written));
+        }
+        if let Some(limiter) = limiter {
+            limiter.acquire(len).await;
+        }
+        writer.write_all(&buf[..len]).await?;
+        written += len as u64;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DirState {
+    Existed,
+    Created,
+    Missing,
+}
+
+/// Everything a restore does on the server, split out so it can run against a fake one in tests.
+/// [`RemoteTarget`] is the real thing.
+trait Remote {
+    type Reader: AsyncRead + Unpin;
+    type Writer: AsyncWrite + Unpin;
+
+    async fn stat(&self, pth: &SimplePath) -> Result<FileStat, Error>;
+    async fn lstat(&self, pth: &SimplePath) -> Result<FileStat, Error>;
+    async fn setstat(&self, pth: &SimplePath, stat: FileStat) -> Result<(), Error>;
+    async fn mkdir(&self, pth: &SimplePath, mode: u32) -> Result<(), Error>;
+    async fn rmdir(&self, pth: &SimplePath) -> Result<(), Error>;
+    async fn readdir(&self, pth: &SimplePath) -> Result<Vec<(PathBuf, FileStat)>, Error>;
+    async fn unlink(&self, pth: &SimplePath) -> Result<(), Error>;
+    async fn rename(&self, src: &SimplePath, dst: &SimplePath) -> Result<(), Error>;
+    async fn symlink(&self, target: &SimplePath, dst: &SimplePath) -> Result<(), Error>;
+    async fn open(&self, pth: &SimplePath) -> Result<Self::Reader, Error>;
+    /// Starts writing a file of `size` bytes, which [`close`](Self::close) finishes.
+    async fn create(
+        &self,
+        pth: &SimplePath,
+        mode: i32,
+        size: u64,
+        transfer: Transfer,
+    ) -> Result<Self::Writer, Error>;
+    async fn close(&self, file: Self::Writer) -> Result<(), Error>;
+    /// Runs `command` and returns what it printed, failing unless it exits 0.
+    async fn output(&self, command: &str) -> Result<String, Error>;
+}
+
+/// Whether writing `dst` would replace something [`RestoreOptions::no_clobber`] keeps.
+async fn is_clobbered(remote: &impl Remote, dst: &SimplePath, opts: &RestoreOptions) -> bool {
+    // lstat, so a link already there is kept even if it dangles
+    opts.no_clobber && remote.lstat(dst).await.is_ok()
+}
+
+async fn mkdir_r<P: Into<SimplePath>>(
+    remote: &impl Remote,
+    pth: P,
+    seen_paths: Arc<RwLock<HashMap<SimplePath, DirState>>>,
+    dir_mode: u32,
+    dry_run: bool,
+) -> Result<(), std::io::Error> {
+    let pth = pth.into();
+    // nothing exists below a directory that had to be created, so those are never stat'ed;
+    // a tree of new directories costs one mkdir each instead of a stat and a mkdir
+    let mut parent_missing = false;
+    for pth in pth.ancestors_rev() {
+        if let Some(&state) = seen_paths.read().await.get(PathStr::new(pth)) {
+            parent_missing = state != DirState::Existed;
+            continue;
+        }
+        let pth = SimplePath::new(pth);
+        let state = if pth.is_root() || (!parent_missing && remote.stat(&pth).await.is_ok()) {
+            DirState::Existed
+        } else if dry_run {
+            info!("would create {}", pth);
+            DirState::Missing
+        } else {
+            debug!("mkdir {}", pth);
+            if let Err(e) = remote.mkdir(&pth, dir_mode).await {
+                // another upload may have created it since we checked, which servers report as
+                // anything from AlreadyExists to a bare failure
+                if remote.stat(&pth).await.is_err() {
+                    return Err(e);
+                }
+            }
+            DirState::Created
+        };
+        parent_missing = state != DirState::Existed;
+        {
+            let mut seen_paths = seen_paths.write().await;
+            seen_paths.insert(pth, state);
+        }
+    }
+    Ok(())
+}
+
+/// Picks the files left over in `tmp_path` by an interrupted run, ignoring anything that is not
+/// directly inside it.
+fn stale_tmp_files(tmp_path: &SimplePath, listing: Vec<(PathBuf, FileStat)>) -> Vec<PathBuf> {
+    listing
+        .into_iter()
+        .filter(|(pth, stat)| stat.is_file() && pth.parent() == Some(tmp_path.as_ref()))
+        .map(|(pth, _)| pth)
+        .collect()
+}
+
+async fn clean_tmp(remote: &impl Remote, tmp_path: &SimplePath) -> Result<(), std::io::Error> {
+    for pth in stale_tmp_files(tmp_path, remote.readdir(tmp_path).await?) {
+        remote.unlink(&SimplePath::from(pth)).await?;
+    }
+    Ok(())
+}
+
+async fn rename_over(
+    remote: &impl Remote,
+    src: &SimplePath,
+    dst: &SimplePath,
+) -> Result<(), std::io::Error> {
+    match remote.rename(src, dst).await {
+        Ok(()) => Ok(()),
+        // sftp v3 servers refuse to rename over an existing file
+        Err(e) if remote.stat(dst).await.is_ok() => {
+            remote.unlink(dst).await.map_err(|_| e)?;
+            remote.rename(src, dst).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn is_complete(stat: &FileStat, size: u64) -> bool {
+    stat.is_file() && stat.size == Some(size)
+}
+
+fn stat_matches(stat: &FileStat, size: u64, mtime: u64) -> bool {
+    is_complete(stat, size) && stat.mtime == Some(mtime)
+}
+
+/// Whether an earlier run already uploaded `upload`. Uploads are renamed into place once
+/// written, so a file at the destination with the right size is a finished one.
+async fn is_uploaded(remote: &impl Remote, upload: &Upload) -> bool {
+    remote
+        .stat(&upload.dst)
+        .await
+        .is_ok_and(|stat| is_complete(&stat, upload.size))
+}
+
+/// Whether the remote already has `upload`, by checksum if one was taken and otherwise by size
+/// and modification time. Files too big to buffer are only hashed once they are streamed, so
+/// they always go by size and time.
+async fn is_unchanged(remote: &impl Remote, upload: &Upload, verify: bool) -> bool {
+    let stat = match remote.stat(&upload.dst).await {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    match upload.sha256.as_ref().filter(|_| verify) {
+        Some(expected) => {
+            stat.is_file()
+                && stat.size == Some(upload.size)
+                && remote_sha256(remote, &upload.dst)
+                    .await
+                    .is_ok_and(|actual| actual == *expected)
+        }
+        None => stat_matches(&stat, upload.size, upload.mtime),
+    }
+}
+
+/// Writes `body` to `tmp_file`, failing with `read_err` if it can't be read.
+async fn send_file(
+    remote: &impl Remote,
+    upload: &Upload,
+    body: impl AsyncRead + Unpin,
+    read_err: impl Fn(Error) -> RestoreError,
+    tmp_file: &SimplePath,
+    opts: &RestoreOptions,
+    limiter: Option<&RateLimiter>,
+) -> Result<(), RestoreError> {
+    let sftp_err = RestoreError::sftp(&upload.dst);
+    let mut file = remote
+        .create(tmp_file, upload.mode, upload.size, opts.transfer)
+        .await
+        .map_err(&sftp_err)?;
+    let bytes = with_timeout(
+        opts.io_timeout,
+        "writing",
+        copy_chunked(body, &mut file, opts.buffer_size, limiter),
+    )
+    .await
+    .map_err(&sftp_err)?
+    .map_err(read_err)?;
+    remote.close(file).await.map_err(&sftp_err)?;
+    if bytes != upload.size {
+        return Err(RestoreError::Transfer {
+            path: upload.dst.clone(),
+            expected: upload.size,
+            wrote: bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Checks what [`send_file`] wrote, sets its times and owner, and renames it into place.
+async fn install_file(
+    remote: &impl Remote,
+    upload: &Upload,
+    tmp_file: &SimplePath,
+    opts: &RestoreOptions,
+) -> Result<(), RestoreError> {
+    let &Upload {
+        ref dst,
+        mtime,
+        owner,
+        ref sha256,
+        ..
+    } = upload;
+
+    let sftp_err = RestoreError::sftp(dst);
+    if let Some(expected) = sha256.as_ref().filter(|_| opts.verify.is_some()) {
+        let actual = remote_sha256(remote, tmp_file).await.map_err(&sftp_err)?;
+        if actual != *expected {
+            remote.unlink(tmp_file).await.map_err(&sftp_err)?;
+            return Err(RestoreError::Checksum {
+                path: dst.clone(),
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    if opts.preserve_times {
+        // scp can carry times too, but setstat works the same for every transfer
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: Some(mtime),
+            mtime: Some(mtime),
+        };
+        remote.setstat(tmp_file, stat).await.map_err(&sftp_err)?;
+    }
+    if !owner.is_empty() {
+        // before the rename, so the file never shows up under the wrong owner
+        let result = set_owner(remote, tmp_file, owner).await;
+        opts.chown_errors.handle(dst, result)?;
+    }
+    rename_over(remote, tmp_file, dst).await.map_err(sftp_err)
+}
+
+#[instrument(skip_all, fields(path = %upload.dst))]
+async fn put_file(
+    remote: &impl Remote,
+    upload: &Upload,
+    body: impl AsyncRead + Unpin,
+    read_err: impl Fn(Error) -> RestoreError,
+    tmp_file: &SimplePath,
+    opts: &RestoreOptions,
+    limiter: Option<&RateLimiter>,
+) -> Result<(), RestoreError> {
+    send_file(remote, upload, body, read_err, tmp_file, opts, limiter).await?;
+    install_file(remote, upload, tmp_file, opts).await
+}
+
+/// Uploads a file [`read_entry`] left in the archive, hashing it on the way if need be. The
+/// archive can't be rewound, so there is no second attempt.
+#[instrument(skip_all, fields(path = %upload.dst))]
+async fn stream_file(
+    remote: &impl Remote,
+    upload: &mut Upload,
+    body: impl AsyncRead + Unpin,
+    tmp_file: &SimplePath,
+    opts: &RestoreOptions,
+    limiter: Option<&RateLimiter>,
+) -> Result<(), RestoreError> {
+    let read_err = RestoreError::Archive;
+    if opts.takes_checksums() {
+        let mut body = HashReader::new(body);
+        send_file(remote, upload, &mut body, read_err, tmp_file, opts, limiter).await?;
+        upload.sha256 = Some(body.hex_digest());
+    } else {
+        send_file(remote, upload, body, read_err, tmp_file, opts, limiter).await?;
+    }
+    install_file(remote, upload, tmp_file, opts).await
+}
+
+/// Fails the upload of `dst` once it has run for `secs`. It fails as a timeout, so it is retried
+/// like a stalled write.
+async fn with_file_timeout<T>(
+    secs: Option<u64>,
+    dst: &SimplePath,
+    fut: impl Future<Output = Result<T, RestoreError>>,
+) -> Result<T, RestoreError> {
+    match secs {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), fut)
+            .await
+            .unwrap_or_else(|_| {
+                Err(RestoreError::sftp(dst)(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("upload timed out after {}s", secs),
+                )))
+            }),
+        None => fut.await,
+    }
+}
+
+/// Runs `op` until it succeeds, fails with a non-transient error, or `retries` is used up,
+/// doubling the delay after every attempt.
+async fn retry<T, F, Fut>(retries: u32, backoff: Duration, mut op: F) -> Result<T, RestoreError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RestoreError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < retries && e.is_transient() => {
+                warn!("{}, retrying", e);
+                tokio::time::sleep(backoff * (1 << attempt.min(16))).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+async fn symlink_over(
+    remote: &impl Remote,
+    dst: &SimplePath,
+    target: &SimplePath,
+) -> Result<(), std::io::Error> {
+    match remote.symlink(target, dst).await {
+        Ok(()) => Ok(()),
+        Err(e) if remote.lstat(dst).await.is_ok() => {
+            remote.unlink(dst).await.map_err(|_| e)?;
+            remote.symlink(target, dst).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The most links followed in a row before giving up, as Linux does with `ELOOP`.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Finds the file the link at `dst` ends up at once any links in between are followed, or
+/// `None` if it dangles, loops, or leads to something other than one of `files`.
+fn follow_link<'a, V>(
+    dst: &SimplePath,
+    links: &'a BTreeMap<SimplePath, SimplePath>,
+    files: &'a HashMap<SimplePath, V>,
+) -> Option<(&'a SimplePath, &'a V)> {
+    let mut target = links.get(dst)?;
+    for _ in 0..MAX_LINK_HOPS {
+        if let Some(file) = files.get_key_value(target) {
+            return Some(file);
+        }
+        target = links.get(target)?;
+    }
+    None
+}
+
+/// Whether `pth`, found on the remote, is one `--delete` should remove: it has to be below the
+/// base, not written by this restore, and not left out by the include and exclude patterns.
+fn is_extra(pth: &SimplePath, opts: &RestoreOptions, kept: &BTreeSet<SimplePath>) -> bool {
+    match pth.strip_prefix(opts.base_path.normalize()) {
+        Some(rel) if rel.depth() > 0 => {
+            !kept.contains(pth)
+                && !pth.starts_with(opts.tmp_path())
+                && is_selected(&rel, &opts.include, &opts.exclude)
+        }
+        _ => false,
+    }
+}
+
+async fn delete_extras(
+    remote: &impl Remote,
+    opts: &RestoreOptions,
+    kept: &BTreeSet<SimplePath>,
+) -> Result<usize, RestoreError> {
+    let mut deleted = 0;
+    let mut extra_dirs = Vec::new();
+    let base_path = match opts.base_path.normalize() {
+        // readdir wants "." for the login directory
+        base if base.is_empty() => SimplePath::new("."),
+        base => base,
+    };
+    let mut pending = vec![base_path];
+    let tmp_path = opts.tmp_path();
+    while let Some(dir) = pending.pop() {
+        let listing = remote
+            .readdir(&dir)
+            .await
+            .map_err(RestoreError::sftp(&dir))?;
+        for (pth, stat) in listing {
+            let pth = SimplePath::from(pth).normalize();
+            let extra = is_extra(&pth, opts, kept);
+            if stat.is_dir() {
+                if pth != tmp_path {
+                    pending.push(pth.clone());
+                }
+                if extra {
+                    extra_dirs.push(pth);
+                }
+            } else if extra && opts.dry_run {
+                info!("would delete {}", pth);
+                deleted += 1;
+            } else if extra {
+                debug!("delete {}", pth);
+                remote
+                    .unlink(&pth)
+                    .await
+                    .map_err(RestoreError::sftp(&pth))?;
+                deleted += 1;
+            }
+        }
+    }
+    // children first, a directory still holding excluded files is left in place
+    extra_dirs.sort_by_key(|d| std::cmp::Reverse(d.depth()));
+    for dir in extra_dirs {
+        if opts.dry_run {
+            info!("would delete {}", dir);
+            deleted += 1;
+        } else if let Err(e) = remote.rmdir(&dir).await {
+            debug!("keeping {}: {}", dir, e);
+        } else {
+            debug!("delete {}", dir);
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Looks up a PAX record, which is always UTF-8 whatever the archive's
+/// charset. async-tar resolves GNU long names and PAX `path` records on its
+/// own, but a PAX `linkpath` record is ignored in favour of the header's
+/// truncated 100-byte link name.
+async fn pax_record<R: AsyncRead + Unpin>(
+    ent: &mut async_tar::Entry<Archive<R>>,
+    key: &str,
+) -> Result<Option<String>, RestoreError> {
+    let extensions = match ent.pax_extensions().await.map_err(RestoreError::Archive)? {
+        Some(extensions) => extensions,
+        None => return Ok(None),
+    };
+    for ext in extensions {
+        let ext = ext.map_err(RestoreError::Archive)?;
+        if ext.key_bytes() == key.as_bytes() {
+            return Ok(Some(
+                String::from_utf8_lossy(ext.value_bytes()).into_owned(),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+fn decode_name(name: &[u8], charset: Option<&'static Encoding>) -> String {
+    match charset {
+        Some(charset) => charset.decode_without_bom_handling(name).0.into_owned(),
+        None => String::from_utf8_lossy(name).into_owned(),
+    }
+}
+
+/// Works out where `ent` goes on the remote, returning its path in the archive, its
+/// destination, the base that destination is under, and whether it is a directory, or `None`
+/// if it is left out.
+async fn entry_paths<R: AsyncRead + Unpin>(
+    ent: &mut async_tar::Entry<Archive<R>>,
+    opts: &RestoreOptions,
+) -> Result<Option<(SimplePath, SimplePath, SimplePath, bool)>, RestoreError> {
+    let entry_type = ent.header().entry_type();
+    let is_link = entry_type.is_symlink() || entry_type.is_hard_link();
+    if !entry_type.is_file() && !entry_type.is_dir() && !is_link {
+        return Ok(None);
+    }
+    let src = match pax_record(ent, "path").await? {
+        Some(src) => src,
+        None => decode_name(&ent.path_bytes(), opts.charset),
+    };
+    // old tars mark directories only by the trailing slash, GNU tar reads them the same way
+    let is_dir =
+        entry_type.is_dir() || (entry_type.is_file() && SimplePath::ends_with_separator(&src));
+    // reject bad names here, not as an opaque failure from the server later
+    let src = SimplePath::try_new(&src)?;
+    let base_path = match opts.absolute_paths {
+        _ if src.is_relative() => opts.base_path.clone(),
+        AbsolutePaths::Reroot => opts.base_path.clone(),
+        AbsolutePaths::Keep => SimplePath::new("/"),
+        AbsolutePaths::Reject => return Err(PathError::Absolute(src.to_string()).into()),
+    };
+    let src = src.strip_prefix("/").unwrap_or(src);
+    let src = match src.strip_components(opts.strip_components) {
+        Some(src) => SimplePath::new_safe(src)?,
+        None => return Ok(None),
+    };
+    if !is_selected(&src, &opts.include, &opts.exclude) {
+        return Ok(None);
+    }
+    let dst = src.canonicalize_against(&base_path);
+    Ok(Some((src, dst, base_path, is_dir)))
+}
+
+/// Reads `ent` into an [`Entry`], or `None` if it is left out. A file over
+/// [`RestoreOptions::max_buffered`] is not read, the caller streams it from `ent` instead.
+async fn read_entry<R: AsyncRead + Unpin>(
+    ent: &mut async_tar::Entry<Archive<R>>,
+    opts: &RestoreOptions,
+) -> Result<Option<Entry>, RestoreError> {
+    let (src, dst, base_path, is_dir) = match entry_paths(ent, opts).await? {
+        Some(paths) => paths,
+        None => return Ok(None),
+    };
+    let entry_type = ent.header().entry_type();
+    let mode = ent.header().mode().map_err(RestoreError::Archive)? & 0o7777;
+    let recorded = if opts.preserve_owner {
+        Owner {
+            uid: Some(ent.header().uid().map_err(RestoreError::Archive)? as u32),
+            gid: Some(ent.header().gid().map_err(RestoreError::Archive)? as u32),
+        }
+    } else {
+        Owner::default()
+    };
+    let owner = opts.chown.unwrap_or_default().or(recorded);
+
+    if is_dir {
+        let mode = opts.recorded_dir_mode(mode);
+        return Ok(Some(Entry::Dir { dst, mode, owner }));
+    }
+    if !entry_type.is_file() && opts.symlinks == Symlinks::Skip {
+        return Ok(Some(Entry::Skipped { dst }));
+    }
+    if !entry_type.is_file() {
+        let link_name = match pax_record(ent, "linkpath").await? {
+            Some(link_name) => link_name,
+            None => ent
+                .link_name_bytes()
+                .map(|name| decode_name(&name, opts.charset))
+                .ok_or_else(|| {
+                    RestoreError::Archive(Error::other(format!("link {} has no target", src)))
+                })?,
+        };
+        SimplePath::try_new(&link_name)?;
+        let target = link_target(&base_path, &dst, &link_name, entry_type.is_hard_link())?;
+        return Ok(Some(Entry::Symlink { dst, target }));
+    }
+
+    let size = ent.header().size().map_err(RestoreError::Archive)?;
+    // checked before anything is buffered or preallocated for the entry
+    match opts.max_file_size {
+        Some(max) if size > max && opts.oversized == Oversized::Skip => {
+            warn!("skip {} [{} bytes is over the limit of {}]", src, size, max);
+            return Ok(Some(Entry::Skipped { dst }));
+        }
+        Some(max) if size > max => {
+            return Err(RestoreError::Archive(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is {} bytes, over the limit of {}", src, size, max),
+            )));
+        }
+        _ => {}
+    }
+    let mtime = ent.header().mtime().map_err(RestoreError::Archive)?;
+    let mode = opts.file_mode(mode) as i32;
+    if opts.dry_run || size > opts.max_buffered {
+        return Ok(Some(Entry::File(Upload {
+            dst,
+            size,
+            mode,
+            mtime,
+            owner,
+            data: opts.dry_run.then(Vec::new),
+            sha256: None,
+        })));
+    }
+    let mut data = Vec::with_capacity(size as usize);
+    // only checked on the remote with --verify, but the manifest wants it too
+    let sha256 = if opts.takes_checksums() {
+        let mut reader = HashReader::new(&mut *ent);
+        reader
+            .read_to_end(&mut data)
+            .await
+            .map_err(RestoreError::Archive)?;
+        Some(reader.hex_digest())
+    } else {
+        ent.read_to_end(&mut data)
+            .await
+            .map_err(RestoreError::Archive)?;
+        None
+    };
+    Ok(Some(Entry::File(Upload {
+        dst,
+        size,
+        mode,
+        mtime,
+        owner,
+        data: Some(data),
+        sha256,
+    })))
+}
+
+/// Reads just the headers of `archive` for every directory [`restore_archive`] will write to,
+/// sorted so parents come before their children, for [`RestoreOptions::precreate_dirs`].
+pub async fn collect_dirs<R: AsyncRead + Unpin>(
+    archive: Archive<R>,
+    opts: &RestoreOptions,
+) -> Result<Vec<SimplePath>, RestoreError> {
+    let mut dirs = BTreeSet::new();
+    let mut entries = archive.entries().map_err(RestoreError::Archive)?;
+    while let Some(ent) = entries.next().await {
+        let mut ent = ent.map_err(RestoreError::Archive)?;
+        let entry_type = ent.header().entry_type();
+        let is_link = entry_type.is_symlink() || entry_type.is_hard_link();
+        if is_link && opts.symlinks == Symlinks::Skip {
+            continue;
+        }
+        let (dst, is_dir) = match entry_paths(&mut ent, opts).await? {
+            Some((_, dst, _, is_dir)) => (dst, is_dir),
+            None => continue,
+        };
+        // with every ancestor listed, a level never waits on a directory of its own
+        dirs.extend(
+            dst.dir_ancestors()
+                .chain(Some(dst.as_str()).filter(|_| is_dir))
+                .filter(|&pth| pth != "/" && !pth.is_empty())
+                .map(SimplePath::new),
+        );
+    }
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    // stable, so each level stays in path order
+    dirs.sort_by_key(SimplePath::depth);
+    Ok(dirs)
+}
+
+/// Formats one line of `sha256sum` output. Like coreutils, a name with a backslash or newline
+/// is escaped and the line marked with a leading backslash.
+fn checksum_line(path: &SimplePath, sha256: &str) -> String {
+    let name = path.as_str();
+    if name.contains(['\\', '\n', '\r']) {
+        let name = name
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{}  {}\n", sha256, name)
+    } else {
+        format!("{}  {}\n", sha256, name)
+    }
+}
+
+/// Lists every file once, sorted by path whatever order the uploads finished in.
+fn checksum_manifest(checksums: Vec<(SimplePath, String)>) -> String {
+    keep_latest(checksums, |(pth, _)| pth)
+        .iter()
+        .map(|(pth, sha256)| checksum_line(pth, sha256))
+        .collect()
+}
+
+/// Uploads `manifest` to `dst` like any other file from the archive.
+async fn write_checksums(
+    remote: &impl Remote,
+    seen_paths: &Arc<RwLock<HashMap<SimplePath, DirState>>>,
+    dst: SimplePath,
+    manifest: String,
+    tmp_file: &SimplePath,
+    opts: &RestoreOptions,
+) -> Result<(), RestoreError> {
+    if opts.dry_run {
+        info!("would write checksums to {}", dst);
+        return Ok(());
+    }
+    if let Some(parent) = dst.parent() {
+        mkdir_r(
+            remote,
+            parent.clone(),
+            seen_paths.clone(),
+            opts.created_dir_mode(),
+            false,
+        )
+        .await
+        .map_err(RestoreError::sftp(&parent))?;
+    }
+    debug!("put {} [{} bytes]", dst, manifest.len());
+    let upload = Upload {
+        dst,
+        size: manifest.len() as u64,
+        mode: opts.file_mode(0o644) as i32,
+        mtime: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        owner: opts.chown.unwrap_or_default(),
+        data: None,
+        sha256: None,
+    };
+    let read_err = RestoreError::Archive;
+    put_file(
+        remote,
+        &upload,
+        manifest.as_bytes(),
+        read_err,
+        tmp_file,
+        opts,
+        None,
+    )
+    .await
+}
+
+/// Sorts `items` by `key`, keeping only the last one pushed for each, since a later entry for
+/// the same path, in the same archive or a later one, replaces what came before.
+fn keep_latest<T, K: Ord>(mut items: Vec<T>, key: impl Fn(&T) -> &K) -> Vec<T> {
+    items.reverse();
+    items.sort_by(|a, b| key(a).cmp(key(b)));
+    items.dedup_by(|a, b| key(a) == key(b));
+    items
+}
+
+/// The server a restore writes to: the session, which runs remote commands, and the one SFTP
+/// channel every phase of a restore shares. Connecting and authenticating the session is left
+/// to the caller, and so is deciding when to [`disconnect`](Self::disconnect).
+#[derive(Clone)]
+pub struct RemoteTarget {
+    session: Arc<AsyncSession<std::net::TcpStream>>,
+    sftp: Arc<AsyncSftp<std::net::TcpStream>>,
+    env: Arc<Vec<(String, String)>>,
+    /// Variables the server wouldn't set, so each is only warned about once
+    refused_env: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl RemoteTarget {
+    /// Opens the SFTP channel on an authenticated `session`.
+    pub async fn new(
+        session: Arc<AsyncSession<std::net::TcpStream>>,
+    ) -> Result<Self, RestoreError> {
+        let sftp = session.sftp().await.map_err(RestoreError::Connect)?;
+        Ok(Self {
+            session,
+            sftp: Arc::new(sftp),
+            env: Arc::default(),
+            refused_env: Arc::default(),
+        })
+    }
+
+    /// Sets `env` in the environment of every command run on the remote, such as the
+    /// `sha256sum` behind [`RestoreOptions::verify`].
+    pub fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = Arc::new(env);
+        self
+    }
+
+    pub fn session(&self) -> &AsyncSession<std::net::TcpStream> {
+        &self.session
+    }
+
+    pub fn sftp(&self) -> &AsyncSftp<std::net::TcpStream> {
+        &self.sftp
+    }
+
+    /// Hangs up, telling the server `description`. A `code` of `None` is an ordinary goodbye.
+    pub async fn disconnect(
+        self,
+        code: Option<DisconnectCode>,
+        description: &str,
+    ) -> Result<(), Error> {
+        // the channel goes first unless a clone still holds it
+        drop(self.sftp);
+        self.session.disconnect(code, description, None).await
+    }
+}
+
+/// A file being written to a [`RemoteTarget`], over either [`Transfer`].
+enum RemoteFile {
+    Scp(AsyncChannel<std::net::TcpStream>),
+    Sftp(AsyncFile<std::net::TcpStream>),
+}
+
+impl AsyncWrite for RemoteFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            RemoteFile::Scp(ch) => Pin::new(ch).poll_write(cx, buf),
+            RemoteFile::Sftp(file) => Pin::new(file).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            RemoteFile::Scp(ch) => Pin::new(ch).poll_flush(cx),
+            RemoteFile::Sftp(file) => Pin::new(file).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            RemoteFile::Scp(ch) => Pin::new(ch).poll_close(cx),
+            RemoteFile::Sftp(file) => Pin::new(file).poll_close(cx),
+        }
+    }
+}
+
+impl Remote for RemoteTarget {
+    type Reader = AsyncFile<std::net::TcpStream>;
+    type Writer = RemoteFile;
+
+    async fn stat(&self, pth: &SimplePath) -> Result<FileStat, Error> {
+        self.sftp.stat(pth).await
+    }
+
+    async fn lstat(&self, pth: &SimplePath) -> Result<FileStat, Error> {
+        self.sftp.lstat(pth).await
+    }
+
+    async fn setstat(&self, pth: &SimplePath, stat: FileStat) -> Result<(), Error> {
+        self.sftp.setstat(pth, stat).await
+    }
+
+    async fn mkdir(&self, pth: &SimplePath, mode: u32) -> Result<(), Error> {
+        self.sftp.mkdir(pth, mode as i32).await
+    }
+
+    async fn rmdir(&self, pth: &SimplePath) -> Result<(), Error> {
+        self.sftp.rmdir(pth).await
+    }
+
+    async fn readdir(&self, pth: &SimplePath) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        self.sftp.readdir(pth).await
+    }
+
+    async fn unlink(&self, pth: &SimplePath) -> Result<(), Error> {
+        self.sftp.unlink(pth).await
+    }
+
+    async fn rename(&self, src: &SimplePath, dst: &SimplePath) -> Result<(), Error> {
+        self.sftp.rename(src, dst, None).await
+    }
+
+    async fn symlink(&self, target: &SimplePath, dst: &SimplePath) -> Result<(), Error> {
+        self.sftp.symlink(target, dst).await
+    }
+
+    async fn open(&self, pth: &SimplePath) -> Result<Self::Reader, Error> {
+        self.sftp.open(pth).await
+    }
+
+    async fn create(
+        &self,
+        pth: &SimplePath,
+        mode: i32,
+        size: u64,
+        transfer: Transfer,
+    ) -> Result<RemoteFile, Error> {
+        match transfer {
+            Transfer::Scp => self
+                .session
+                .scp_send(Path::new(pth.as_str()), mode, size, None)
+                .await
+                .map(RemoteFile::Scp),
+            Transfer::Sftp => self
+                .sftp
+                .open_mode(
+                    pth,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    mode,
+                    OpenType::File,
+                )
+                .await
+                .map(RemoteFile::Sftp),
+        }
+    }
+
+    async fn close(&self, file: RemoteFile) -> Result<(), Error> {
+        match file {
+            RemoteFile::Scp(mut ch) => {
+                ch.send_eof().await?;
+                ch.wait_eof().await?;
+                ch.close().await?;
+                ch.wait_close().await
+            }
+            RemoteFile::Sftp(mut file) => file.close().await,
+        }
+    }
+
+    async fn output(&self, command: &str) -> Result<String, Error> {
+        remote_output(self, command).await
+    }
+}
+
+/// Writes every entry of `archive` below `opts.base_path` on the remote, going through
+/// [`RestoreOptions::temp_dir`] so no file is ever seen half written.
+pub async fn restore_archive<R: AsyncRead + Unpin>(
+    remote: &RemoteTarget,
+    archive: Archive<R>,
+    opts: &RestoreOptions,
+) -> Result<RestoreStats, RestoreError> {
+    restore_archives(remote, [archive], opts).await
+}
+
+/// Like [`restore_archive`] for a backup split over several archives, restored one after
+/// another over the same connection. Where two have the same path the later one wins.
+pub async fn restore_archives<R: AsyncRead + Unpin>(
+    remote: &RemoteTarget,
+    archives: impl IntoIterator<Item = Archive<R>>,
+    opts: &RestoreOptions,
+) -> Result<RestoreStats, RestoreError> {
+    let hook = |command| run_hook(remote, command);
+    with_hooks(opts, hook, restore_all(remote, archives, opts)).await
+}
+
+async fn restore_all<R: AsyncRead + Unpin>(
+    remote: &impl Remote,
+    archives: impl IntoIterator<Item = Archive<R>>,
+    opts: &RestoreOptions,
+) -> Result<RestoreStats, RestoreError> {
+    let started = Instant::now();
+    let seen_paths = Arc::new(RwLock::new(HashMap::<SimplePath, DirState>::new()));
+
+    let tmp_path = opts.tmp_path();
+    if !opts.dry_run {
+        mkdir_r(
+            remote,
+            tmp_path.as_str(),
+            seen_paths.clone(),
+            opts.created_dir_mode(),
+            false,
+        )
+        .await
+        .map_err(RestoreError::sftp(&tmp_path))?;
+        if opts.clean_tmp {
+            clean_tmp(remote, &tmp_path)
+                .await
+                .map_err(RestoreError::sftp(&tmp_path))?;
+        }
+        if opts.temp_dir.as_str() != DEFAULT_TEMP_DIR {
+            let base_path = opts.under_base(&SimplePath::default());
+            match same_filesystem(remote, &base_path, &tmp_path).await {
+                Ok(true) => {}
+                Ok(false) => warn!(
+                    "{} is on a different filesystem than {}, renaming uploads into place will fail",
+                    tmp_path, base_path
+                ),
+                Err(e) => debug!("could not compare filesystems: {}", e),
+            }
+        }
+    }
+    // each level only needs the one before it, so a level's directories can go in parallel
+    for level in opts.precreate_dirs.chunk_by(|a, b| a.depth() == b.depth()) {
+        stream::iter(level.iter().map(Ok))
+            .try_for_each_concurrent(opts.jobs.get(), |dir| {
+                let seen_paths = seen_paths.clone();
+                async move {
+                    mkdir_r(
+                        remote,
+                        dir.clone(),
+                        seen_paths,
+                        opts.created_dir_mode(),
+                        opts.dry_run,
+                    )
+                    .await
+                    .map_err(RestoreError::sftp(dir))
+                }
+            })
+            .await?;
+    }
+    let tmp_count = AtomicUsize::new(0);
+    let dir_modes = Mutex::new(Vec::new());
+    let tally = Tally::default();
+    let in_flight = Mutex::new(BTreeSet::new());
+    let limiter = opts.limit_rate.map(RateLimiter::new);
+    let kept = Mutex::new(BTreeSet::new());
+    let checksums = Mutex::new(Vec::new());
+    // with --symlinks follow, links are only resolved once every archive is read, since the
+    // file one points to can come after it
+    let followed = Mutex::new(Vec::new());
+    let followed_files = Mutex::new(HashMap::new());
+    // when each path's latest write finishes, so a path the archive has twice is written in order
+    let writing = Mutex::new(HashMap::new());
+
+    let write_entry = |entry: Entry, body: Option<async_tar::Entry<Archive<R>>>| {
+        let seen_paths = seen_paths.clone();
+        let (dry_run, dir_mode) = (opts.dry_run, opts.created_dir_mode());
+        let tally = &tally;
+        let (dir_modes, in_flight, kept) = (&dir_modes, &in_flight, &kept);
+        let (checksums, verify) = (&checksums, opts.verify.is_some());
+        let (followed, followed_files) = (&followed, &followed_files);
+        let limiter = limiter.as_ref();
+        let tmp_file = tmp_path.join(format!(
+            "{}-{}",
+            std::process::id(),
+            tmp_count.fetch_add(1, Ordering::Relaxed)
+        ));
+        let dst = entry.dst().clone();
+        let (done, finished) = oneshot::channel::<()>();
+        let before = writing
+            .lock()
+            .unwrap()
+            .insert(dst.clone(), finished.shared());
+        let write = async move {
+            if let Some(before) = before {
+                let _ = before.await;
+            }
+            // dropped once this write is over, letting the next one to the same path go
+            let _done = done;
+            if opts.delete {
+                let mut kept = kept.lock().unwrap();
+                kept.extend(entry.dst().ancestors().map(SimplePath::new));
+            }
+            if let Entry::Skipped { .. } = entry {
+                return Ok(());
+            }
+            if !matches!(entry, Entry::Dir { .. }) && is_clobbered(remote, entry.dst(), opts).await
+            {
+                debug!("skip {} [already exists]", entry.dst());
+                tally.skip();
+                return Ok(());
+            }
+            if let Some(parent) = entry.dst().parent() {
+                mkdir_r(
+                    remote,
+                    parent.clone(),
+                    seen_paths.clone(),
+                    dir_mode,
+                    dry_run,
+                )
+                .await
+                .map_err(RestoreError::sftp(&parent))?;
+            }
+            match entry {
+                Entry::Dir { dst, mode, owner } => {
+                    mkdir_r(remote, dst.clone(), seen_paths, dir_mode, dry_run)
+                        .await
+                        .map_err(RestoreError::sftp(&dst))?;
+                    if (opts.preserve_permissions || !owner.is_empty()) && !dry_run {
+                        // applied once everything is written in case the mode is read-only
+                        dir_modes.lock().unwrap().push((dst, mode, owner));
+                    }
+                    tally.dir();
+                    Ok(())
+                }
+                Entry::File(mut upload) => {
+                    let record = |upload: &Upload| {
+                        let dst = &upload.dst;
+                        if let (Some(_), Some(sha256)) = (&opts.write_checksums, &upload.sha256) {
+                            let rel = dst.relative_to(&opts.base_path).unwrap_or(dst.clone());
+                            checksums.lock().unwrap().push((rel, sha256.clone()));
+                        }
+                        if opts.symlinks == Symlinks::Follow {
+                            let copy = Upload {
+                                dst: dst.clone(),
+                                data: None,
+                                sha256: upload.sha256.clone(),
+                                ..*upload
+                            };
+                            followed_files.lock().unwrap().insert(dst.clone(), copy);
+                        }
+                    };
+                    let skip = if opts.skip_unchanged && is_unchanged(remote, &upload, verify).await
+                    {
+                        Some("unchanged")
+                    } else if opts.resume && is_uploaded(remote, &upload).await {
+                        Some("already uploaded")
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = skip {
+                        debug!("skip {} [{}]", upload.dst, reason);
+                        if let Some(body) = body.filter(|_| opts.write_checksums.is_some()) {
+                            // never hashed since it wasn't buffered, but the manifest lists it
+                            let mut body = HashReader::new(body);
+                            futures::io::copy(&mut body, &mut futures::io::sink())
+                                .await
+                                .map_err(RestoreError::Archive)?;
+                            upload.sha256 = Some(body.hex_digest());
+                        }
+                        record(&upload);
+                        tally.skip();
+                        return Ok(());
+                    }
+                    let dst = upload.dst.clone();
+                    let result = if dry_run {
+                        info!("would put {} [{} bytes]", dst, upload.size);
+                        Ok(())
+                    } else {
+                        debug!("put {} [{} bytes]", dst, upload.size);
+                        in_flight.lock().unwrap().insert(tmp_file.clone());
+                        let result = match body {
+                            Some(body) => {
+                                let stream = stream_file(
+                                    remote,
+                                    &mut upload,
+                                    body,
+                                    &tmp_file,
+                                    opts,
+                                    limiter,
+                                );
+                                with_file_timeout(opts.timeout_per_file, &dst, stream).await
+                            }
+                            None => {
+                                retry(opts.retries, Duration::from_secs(1), || {
+                                    let data = upload.data.as_deref().unwrap_or_default();
+                                    let read_err = RestoreError::Archive;
+                                    with_file_timeout(
+                                        opts.timeout_per_file,
+                                        &dst,
+                                        put_file(
+                                            remote, &upload, data, read_err, &tmp_file, opts,
+                                            limiter,
+                                        ),
+                                    )
+                                })
+                                .await
+                            }
+                        };
+                        if result.is_err() {
+                            // the restore may carry on without this file
+                            let _ = remote.unlink(&tmp_file).await;
+                        }
+                        in_flight.lock().unwrap().remove(&tmp_file);
+                        result
+                    };
+                    record(&upload);
+                    result?;
+                    tally.file(&upload);
+                    Ok(())
+                }
+                Entry::Symlink { dst, target } if opts.symlinks == Symlinks::Follow => {
+                    let target = dst.parent().unwrap_or_default().join(&target);
+                    followed.lock().unwrap().push((dst, target.normalize()));
+                    Ok(())
+                }
+                Entry::Symlink { dst, target } if dry_run => {
+                    info!("would link {} -> {}", dst, target);
+                    tally.link();
+                    Ok(())
+                }
+                Entry::Symlink { dst, target } => {
+                    debug!("link {} -> {}", dst, target);
+                    symlink_over(remote, &dst, &target)
+                        .await
+                        .map_err(RestoreError::sftp(&dst))?;
+                    tally.link();
+                    Ok(())
+                }
+                Entry::Skipped { .. } => unreachable!("returned before anything was written"),
+            }
+        };
+        async move { tally.settle(&dst, write.await, opts.on_error) }
+    };
+
+    for archive in archives {
+        let pipeline = archive
+            .entries()
+            .map_err(RestoreError::Archive)?
+            .map_err(RestoreError::Archive)
+            .try_filter_map(|mut ent| {
+                let write_entry = &write_entry;
+                async move {
+                    match read_entry(&mut ent, opts).await? {
+                        // too big to buffer, so it is written before the archive moves past it
+                        Some(entry @ Entry::File(Upload { data: None, .. })) => {
+                            write_entry(entry, Some(ent)).await?;
+                            Ok(None)
+                        }
+                        entry => Ok(entry),
+                    }
+                }
+            })
+            .try_for_each_concurrent(opts.jobs.get(), |entry| write_entry(entry, None));
+        // dropping the pipeline stops every upload still running
+        let result = tokio::select! {
+            res = pipeline => res,
+            _ = opts.cancel.cancelled() => Err(RestoreError::Interrupted),
+        };
+        if result.is_err() {
+            let in_flight = std::mem::take(&mut *in_flight.lock().unwrap());
+            for tmp_file in in_flight {
+                let _ = remote.unlink(&tmp_file).await;
+            }
+        }
+        result?;
+    }
+
+    if opts.symlinks == Symlinks::Follow {
+        let files = followed_files.into_inner().unwrap();
+        let links = BTreeMap::from_iter(followed.into_inner().unwrap());
+        for dst in links.keys() {
+            let (src, file) = match follow_link(dst, &links, &files) {
+                Some(found) => found,
+                None => {
+                    warn!("skip {} [does not lead to a file in the archive]", dst);
+                    continue;
+                }
+            };
+            let copy = Upload {
+                dst: dst.clone(),
+                data: None,
+                sha256: file.sha256.clone(),
+                ..*file
+            };
+            if opts.dry_run {
+                info!("would copy {} to {}", src, dst);
+            } else {
+                // the file is already on the remote, so it is read back rather than kept around
+                debug!("copy {} to {} [{} bytes]", src, dst, file.size);
+                let tmp_file = tmp_path.join(format!(
+                    "{}-{}",
+                    std::process::id(),
+                    tmp_count.fetch_add(1, Ordering::Relaxed)
+                ));
+                let result = retry(opts.retries, Duration::from_secs(1), || {
+                    with_file_timeout(opts.timeout_per_file, dst, async {
+                        let body = remote.open(src).await.map_err(RestoreError::sftp(src))?;
+                        let read_err = RestoreError::sftp(src);
+                        let limiter = limiter.as_ref();
+                        put_file(remote, &copy, body, read_err, &tmp_file, opts, limiter).await
+                    })
+                })
+                .await;
+                if result.is_err() {
+                    let _ = remote.unlink(&tmp_file).await;
+                    tally.settle(dst, result, opts.on_error)?;
+                    continue;
+                }
+            }
+            if let (Some(_), Some(sha256)) = (&opts.write_checksums, &file.sha256) {
+                let rel = dst.relative_to(&opts.base_path).unwrap_or(dst.clone());
+                checksums.lock().unwrap().push((rel, sha256.clone()));
+            }
+            tally.file(&copy);
+        }
+    }
+
+    let deleted = if opts.delete {
+        delete_extras(remote, opts, &kept.into_inner().unwrap()).await?
+    } else {
+        0
+    };
+    if let Some(dst) = opts.checksums_path() {
+        let manifest = checksum_manifest(checksums.into_inner().unwrap());
+        let tmp_file = tmp_path.join(format!("{}-checksums", std::process::id()));
+        write_checksums(remote, &seen_paths, dst, manifest, &tmp_file, opts).await?;
+    }
+    let dir_modes = keep_latest(dir_modes.into_inner().unwrap(), |(dst, _, _)| dst);
+    if let Some(chown) = opts.chown.filter(|_| !opts.dry_run) {
+        // directories mkdir_r had to create on the way, the archive's own come next
+        let listed: BTreeSet<_> = dir_modes.iter().map(|(dst, _, _)| dst).collect();
+        let created: Vec<_> = seen_paths
+            .read()
+            .await
+            .iter()
+            .filter(|(pth, state)| **state == DirState::Created && !listed.contains(pth))
+            .map(|(pth, _)| pth.clone())
+            .collect();
+        for dst in created {
+            let result = set_owner(remote, &dst, chown).await;
+            opts.chown_errors.handle(&dst, result)?;
+        }
+    }
+    for (dst, mode, owner) in dir_modes.into_iter().rev() {
+        if !owner.is_empty() {
+            let result = set_owner(remote, &dst, owner).await;
+            opts.chown_errors.handle(&dst, result)?;
+        }
+        if !opts.preserve_permissions {
+            continue;
+        }
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(mode),
+            atime: None,
+            mtime: None,
+        };
+        remote
+            .setstat(&dst, stat)
+            .await
+            .map_err(RestoreError::sftp(&dst))?;
+    }
+
+    let created_dirs = seen_paths
+        .read()
+        .await
+        .iter()
+        .filter(|(pth, state)| **state != DirState::Existed && **pth != tmp_path)
+        .count();
+    Ok(tally.into_stats(created_dirs, deleted, started.elapsed()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_globset() {
+        let exclude = build_globset(&["*.log".to_owned(), "cache/**".to_owned()]).unwrap();
+        assert!(exclude.is_match("debug.log"));
+        assert!(exclude.is_match("var/log/syslog.log"));
+        assert!(exclude.is_match("cache/a"));
+        assert!(exclude.is_match("cache/a/b.bin"));
+        assert!(!exclude.is_match("cache"));
+        assert!(!exclude.is_match("home/cache/a"));
+        assert!(!exclude.is_match("logs/debug.txt"));
+
+        assert!(build_globset(&[]).unwrap().is_empty());
+        assert!(build_globset(&["a[".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_is_selected() {
+        let globs = |patterns: &[&str]| {
+            build_globset(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+        };
+        let none = globs(&[]);
+        let alice = globs(&["home/alice/**"]);
+        let logs = globs(&["*.log"]);
+        let selected =
+            |pth: &str, include, exclude| is_selected(&SimplePath::new(pth), include, exclude);
+
+        assert!(selected("etc/passwd", &none, &none));
+
+        assert!(selected("home/alice/notes.txt", &alice, &none));
+        assert!(!selected("home/bob/notes.txt", &alice, &none));
+
+        assert!(selected("home/bob/notes.txt", &none, &logs));
+        assert!(!selected("var/debug.log", &none, &logs));
+
+        assert!(selected("home/alice/notes.txt", &alice, &logs));
+        assert!(!selected("home/alice/debug.log", &alice, &logs));
+        assert!(!selected("home/bob/notes.txt", &alice, &logs));
+    }
+
+    #[test]
+    fn test_stale_tmp_files() {
+        let stat = |perm| FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(perm),
+            atime: None,
+            mtime: None,
+        };
+        let tmp_path = SimplePath::new("backup/.tmp");
+        let listing = vec![
+            (PathBuf::from("backup/.tmp/1234-0"), stat(0o100644)),
+            (PathBuf::from("backup/.tmp/nested"), stat(0o040755)),
+            (PathBuf::from("backup/data"), stat(0o100644)),
+        ];
+        assert_eq!(
+            stale_tmp_files(&tmp_path, listing),
+            vec![PathBuf::from("backup/.tmp/1234-0")]
+        );
+    }
+
+    #[test]
+    fn test_is_extra() {
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv/restore"),
+            exclude: build_globset(&["*.log".to_owned()]).unwrap(),
+            ..Default::default()
+        };
+        let kept: BTreeSet<_> = SimplePath::new("/srv/restore/a/b")
+            .ancestors()
+            .map(SimplePath::new)
+            .collect();
+        let extra = |pth: &str| is_extra(&SimplePath::new(pth), &opts, &kept);
+
+        assert!(extra("/srv/restore/a/c"));
+        assert!(extra("/srv/restore/old"));
+        assert!(!extra("/srv/restore/a"));
+        assert!(!extra("/srv/restore/a/b"));
+        assert!(!extra("/srv/restore/debug.log"));
+        assert!(!extra("/srv/restore/.tmp"));
+        assert!(!extra("/srv/restore/.tmp/1234-0"));
+        assert!(!extra("/srv/restore"));
+        assert!(!extra("/srv/other"));
+        assert!(!extra("/srv"));
+
+        let opts = RestoreOptions::default();
+        let kept = BTreeSet::from([SimplePath::new("a")]);
+        assert!(is_extra(&SimplePath::new("b"), &opts, &kept));
+        assert!(!is_extra(&SimplePath::new("a"), &opts, &kept));
+        assert!(!is_extra(&SimplePath::new(".tmp/1234-0"), &opts, &kept));
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let opts = |temp_dir: &str| RestoreOptions {
+            base_path: SimplePath::new("/srv/restore"),
+            temp_dir: SimplePath::new(temp_dir),
+            ..Default::default()
+        };
+        assert_eq!(
+            opts(DEFAULT_TEMP_DIR).tmp_path().as_str(),
+            "/srv/restore/.tmp"
+        );
+        assert_eq!(
+            opts("./staging/").tmp_path().as_str(),
+            "/srv/restore/staging"
+        );
+        assert_eq!(opts("../staging").tmp_path().as_str(), "/srv/staging");
+        assert_eq!(opts("/var/tmp/x").tmp_path().as_str(), "/var/tmp/x");
+        assert_eq!(RestoreOptions::default().tmp_path().as_str(), ".tmp");
+
+        // --delete leaves a custom temp directory alone, and no longer spares .tmp
+        let kept = BTreeSet::new();
+        let staging = opts("staging");
+        let extra = |pth: &str| is_extra(&SimplePath::new(pth), &staging, &kept);
+        assert!(!extra("/srv/restore/staging"));
+        assert!(!extra("/srv/restore/staging/1234-0"));
+        assert!(extra("/srv/restore/.tmp"));
+        assert!(extra("/srv/restore/stagingx"));
+    }
+
+    /// A server kept in memory, yielding in every call so concurrent callers interleave.
+    #[derive(Default)]
+    struct FakeRemote {
+        tree: Mutex<BTreeMap<SimplePath, (FileStat, Vec<u8>)>>,
+        stats: AtomicUsize,
+        mkdirs: AtomicUsize,
+        /// Paths nothing can be renamed to, as if the server denied permission
+        refused: BTreeSet<SimplePath>,
+    }
+
+    /// A file [`FakeRemote`] is writing.
+    struct FakeFile {
+        pth: SimplePath,
+        mode: i32,
+        data: Vec<u8>,
+    }
+
+    impl AsyncWrite for FakeFile {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            self.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn fake_stat(perm: u32, size: usize) -> FileStat {
+        FileStat {
+            size: Some(size as u64),
+            uid: None,
+            gid: None,
+            perm: Some(perm),
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    impl FakeRemote {
+        fn with_dirs(dirs: &[&str]) -> Self {
+            let fake = FakeRemote::default();
+            for dir in dirs {
+                fake.put(dir, fake_stat(0o040755, 0), Vec::new());
+            }
+            fake
+        }
+
+        fn put(&self, pth: &str, stat: FileStat, data: Vec<u8>) {
+            let mut tree = self.tree.lock().unwrap();
+            tree.insert(SimplePath::new(pth), (stat, data));
+        }
+
+        fn get(&self, pth: &str) -> Option<(FileStat, Vec<u8>)> {
+            self.tree
+                .lock()
+                .unwrap()
+                .get(&SimplePath::new(pth))
+                .cloned()
+        }
+
+        fn file(&self, pth: &str) -> Option<String> {
+            self.get(pth)
+                .filter(|(stat, _)| stat.is_file())
+                .map(|(_, data)| String::from_utf8(data).unwrap())
+        }
+
+        fn paths(&self) -> Vec<String> {
+            let tree = self.tree.lock().unwrap();
+            tree.keys().map(|pth| pth.to_string()).collect()
+        }
+
+        /// Fails unless the directory `pth` would go in exists.
+        fn check_parent(&self, pth: &SimplePath) -> Result<(), Error> {
+            match pth.parent() {
+                Some(parent) if !parent.is_empty() && !parent.is_root() => {
+                    match self.tree.lock().unwrap().get(&parent) {
+                        Some((stat, _)) if stat.is_dir() => Ok(()),
+                        _ => Err(Error::from(ErrorKind::NotFound)),
+                    }
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Remote for FakeRemote {
+        type Reader = futures::io::Cursor<Vec<u8>>;
+        type Writer = FakeFile;
+
+        async fn stat(&self, pth: &SimplePath) -> Result<FileStat, Error> {
+            self.stats.fetch_add(1, Ordering::Relaxed);
+            let stat = self.lstat(pth).await?;
+            match stat.file_type().is_symlink() {
+                true => {
+                    let target = String::from_utf8(self.get(pth.as_str()).unwrap().1).unwrap();
+                    let target = pth.parent().unwrap_or_default().join(target).normalize();
+                    Box::pin(self.stat(&target)).await
+                }
+                false => Ok(stat),
+            }
+        }
+
+        async fn lstat(&self, pth: &SimplePath) -> Result<FileStat, Error> {
+            tokio::task::yield_now().await;
+            let tree = self.tree.lock().unwrap();
+            let (stat, _) = tree.get(pth).ok_or(Error::from(ErrorKind::NotFound))?;
+            Ok(stat.clone())
+        }
+
+        async fn setstat(&self, pth: &SimplePath, stat: FileStat) -> Result<(), Error> {
+            let mut tree = self.tree.lock().unwrap();
+            let (old, _) = tree.get_mut(pth).ok_or(Error::from(ErrorKind::NotFound))?;
+            if let Some(perm) = stat.perm {
+                old.perm = Some(old.perm.unwrap_or(0) & 0o170000 | perm & 0o7777);
+            }
+            old.uid = stat.uid.or(old.uid);
+            old.gid = stat.gid.or(old.gid);
+            old.atime = stat.atime.or(old.atime);
+            old.mtime = stat.mtime.or(old.mtime);
+            Ok(())
+        }
+
+        async fn mkdir(&self, pth: &SimplePath, mode: u32) -> Result<(), Error> {
+            self.mkdirs.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            self.check_parent(pth)?;
+            let mut tree = self.tree.lock().unwrap();
+            match tree.contains_key(pth) {
+                true => Err(Error::from(ErrorKind::AlreadyExists)),
+                false => {
+                    tree.insert(pth.clone(), (fake_stat(0o040000 | mode, 0), Vec::new()));
+                    Ok(())
+                }
+            }
+        }
+
+        async fn rmdir(&self, pth: &SimplePath) -> Result<(), Error> {
+            match self.readdir(pth).await?.is_empty() {
+                true => self.tree.lock().unwrap().remove(pth).map(drop),
+                false => None,
+            }
+            .ok_or(Error::other("failure"))
+        }
+
+        async fn readdir(&self, pth: &SimplePath) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+            match self.stat(pth).await {
+                Ok(stat) if stat.is_dir() => {}
+                _ => return Err(Error::from(ErrorKind::NotFound)),
+            }
+            let tree = self.tree.lock().unwrap();
+            let dir = pth.normalize();
+            Ok(tree
+                .iter()
+                .filter(|(child, _)| child.parent().map(|p| p.normalize()) == Some(dir.clone()))
+                .map(|(child, (stat, _))| (PathBuf::from(child.as_str()), stat.clone()))
+                .collect())
+        }
+
+        async fn unlink(&self, pth: &SimplePath) -> Result<(), Error> {
+            tokio::task::yield_now().await;
+            let mut tree = self.tree.lock().unwrap();
+            match tree.get(pth) {
+                Some((stat, _)) if !stat.is_dir() => tree.remove(pth).map(drop),
+                _ => None,
+            }
+            .ok_or(Error::from(ErrorKind::NotFound))
+        }
+
+        async fn rename(&self, src: &SimplePath, dst: &SimplePath) -> Result<(), Error> {
+            tokio::task::yield_now().await;
+            self.check_parent(dst)?;
+            if self.refused.contains(dst) {
+                return Err(Error::from(ErrorKind::PermissionDenied));
+            }
+            let mut tree = self.tree.lock().unwrap();
+            // like an sftp v3 server, nothing is replaced
+            if tree.contains_key(dst) {
+                return Err(Error::other("failure"));
+            }
+            let node = tree.remove(src).ok_or(Error::from(ErrorKind::NotFound))?;
+            tree.insert(dst.clone(), node);
+            Ok(())
+        }
+
+        async fn symlink(&self, target: &SimplePath, dst: &SimplePath) -> Result<(), Error> {
+            self.check_parent(dst)?;
+            let mut tree = self.tree.lock().unwrap();
+            if tree.contains_key(dst) {
+                return Err(Error::other("failure"));
+            }
+            let data = target.as_str().as_bytes().to_vec();
+            tree.insert(dst.clone(), (fake_stat(0o120777, data.len()), data));
+            Ok(())
+        }
+
+        async fn open(&self, pth: &SimplePath) -> Result<Self::Reader, Error> {
+            match self.get(pth.as_str()) {
+                Some((stat, data)) if stat.is_file() => Ok(futures::io::Cursor::new(data)),
+                _ => Err(Error::from(ErrorKind::NotFound)),
+            }
+        }
+
+        async fn create(
+            &self,
+            pth: &SimplePath,
+            mode: i32,
+            _: u64,
+            _: Transfer,
+        ) -> Result<FakeFile, Error> {
+            tokio::task::yield_now().await;
+            self.check_parent(pth)?;
+            Ok(FakeFile {
+                pth: pth.clone(),
+                mode,
+                data: Vec::new(),
+            })
+        }
+
+        async fn close(&self, file: FakeFile) -> Result<(), Error> {
+            // a bigger file takes longer, as it would over the network
+            for _ in 0..file.data.len() {
+                tokio::task::yield_now().await;
+            }
+            let stat = fake_stat(0o100000 | file.mode as u32, file.data.len());
+            self.put(file.pth.as_str(), stat, file.data);
+            Ok(())
+        }
+
+        /// Runs the few commands a restore uses, taking options like coreutils would.
+        async fn output(&self, command: &str) -> Result<String, Error> {
+            let words = shell_words(command);
+            let (name, args) = match words.split_first().unwrap() {
+                (name, [c, format, args @ ..]) if name == "stat" && c == "-c" && format == "%d" => {
+                    ("stat -c %d", args)
+                }
+                (name, args) => (name.as_str(), args),
+            };
+            let (opts, files): (Vec<_>, Vec<_>) = match args.iter().position(|a| a == "--") {
+                Some(i) => (args[..i].iter().collect(), args[i + 1..].iter().collect()),
+                None => args.iter().partition(|a| a.starts_with('-')),
+            };
+            let mut out = String::new();
+            match (name, &opts[..]) {
+                ("sha256sum", []) => {
+                    for file in files {
+                        let data = self.open(&SimplePath::new(file)).await?.into_inner();
+                        out += &format!("{:x}  {}\n", Sha256::digest(data), file);
+                    }
+                }
+                // everything is on the one filesystem
+                ("stat -c %d", []) => {
+                    for file in files {
+                        self.stat(&SimplePath::new(file)).await?;
+                        out += "2049\n";
+                    }
+                }
+                ("sha256sum" | "stat -c %d", [opt, ..]) => {
+                    return Err(Error::other(format!(
+                        "{}: unrecognized option '{}'",
+                        name, opt
+                    )))
+                }
+                _ => return Err(Error::other(format!("{}: command not found", command))),
+            }
+            Ok(out)
+        }
+    }
+
+    /// Splits `command` into words like `sh` would, as far as [`shell_quote`] needs.
+    fn shell_words(command: &str) -> Vec<String> {
+        let (mut words, mut word, mut quoted) = (Vec::new(), None::<String>, false);
+        let mut chars = command.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => {
+                    quoted = !quoted;
+                    word.get_or_insert_with(String::new);
+                }
+                '\\' if !quoted => word.get_or_insert_with(String::new).extend(chars.next()),
+                ' ' if !quoted => words.extend(word.take()),
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+        words
+    }
+
+    #[tokio::test]
+    async fn test_remote_sha256() {
+        let fake = FakeRemote::default();
+        for name in ["-c", "--help", "it's"] {
+            fake.put(name, fake_stat(0o100644, 2), b"hi".to_vec());
+            let sum = remote_sha256(&fake, &SimplePath::new(name)).await.unwrap();
+            assert_eq!(sum, format!("{:x}", Sha256::digest(b"hi")), "{}", name);
+        }
+        assert!(remote_sha256(&fake, &SimplePath::new("missing"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_same_filesystem() {
+        let fake = FakeRemote::with_dirs(&["-c", "--help"]);
+        let (a, b) = (SimplePath::new("-c"), SimplePath::new("--help"));
+        assert!(same_filesystem(&fake, &a, &b).await.unwrap());
+        assert!(same_filesystem(&fake, &a, &SimplePath::new("missing"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_clobber() {
+        let fake = FakeRemote::with_dirs(&["/srv/etc"]);
+        fake.put("/srv/etc/motd", fake_stat(0o100644, 0), Vec::new());
+        let (existing, missing) = (
+            SimplePath::new("/srv/etc/motd"),
+            SimplePath::new("/srv/etc/issue"),
+        );
+
+        let opts = RestoreOptions::default();
+        assert!(!is_clobbered(&fake, &existing, &opts).await);
+        let opts = RestoreOptions {
+            no_clobber: true,
+            ..Default::default()
+        };
+        assert!(is_clobbered(&fake, &existing, &opts).await);
+        assert!(!is_clobbered(&fake, &missing, &opts).await);
+        // skipping is decided on the remote alone, it never writes
+        assert_eq!(fake.mkdirs.load(Ordering::Relaxed), 0);
+        assert_eq!(fake.paths(), ["/srv/etc", "/srv/etc/motd"]);
+    }
+
+    #[tokio::test]
+    async fn test_mkdir_r_race() {
+        let fake = FakeRemote::with_dirs(&["/"]);
+        let seen_paths = Arc::new(RwLock::new(HashMap::new()));
+        let (a, b) = futures::join!(
+            mkdir_r(&fake, "/srv/a/b/c", seen_paths.clone(), 0o755, false),
+            mkdir_r(&fake, "/srv/a/b/c", seen_paths.clone(), 0o755, false),
+        );
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(
+            fake.paths(),
+            ["/", "/srv", "/srv/a", "/srv/a/b", "/srv/a/b/c"]
+        );
+        // both callers raced to create at least the first directory
+        assert!(fake.mkdirs.load(Ordering::Relaxed) > 4);
+    }
+
+    #[tokio::test]
+    async fn test_mkdir_r_round_trips() {
+        let fake = FakeRemote::with_dirs(&["/", "/srv", "/srv/a", "/srv/a/b"]);
+        let seen_paths = Arc::new(RwLock::new(HashMap::new()));
+        // the directory of each file in a 1000 file archive, six below the root
+        for i in 0..1000 {
+            let pth = format!("/srv/a/b/c/d{}/e{}", i % 10, i % 100);
+            mkdir_r(&fake, pth.as_str(), seen_paths.clone(), 0o755, false)
+                .await
+                .unwrap();
+        }
+        // stat'ing every ancestor of every file would take 6000 round trips, and stat'ing
+        // each directory once 114 plus the 111 mkdirs; below c nothing is stat'ed at all
+        assert_eq!(fake.stats.load(Ordering::Relaxed), 4);
+        assert_eq!(fake.mkdirs.load(Ordering::Relaxed), 111);
+        assert_eq!(fake.paths().len(), 115);
+    }
+
+    #[tokio::test]
+    async fn test_delete_oversized() {
+        let data = tar_of(&[("big.bin", "0123456789"), ("small.txt", "ok")]).await;
+        let fake = FakeRemote::with_dirs(&["/", "/srv"]);
+        fake.put("/srv/big.bin", fake_stat(0o100644, 3), b"old".to_vec());
+        fake.put("/srv/gone.txt", fake_stat(0o100644, 3), b"old".to_vec());
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            max_file_size: Some(4),
+            delete: true,
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        // skipped, but the archive has it, so the remote copy stays
+        assert_eq!(fake.file("/srv/big.bin").as_deref(), Some("old"));
+        assert_eq!(fake.file("/srv/small.txt").as_deref(), Some("ok"));
+        assert_eq!(fake.file("/srv/gone.txt"), None);
+        assert_eq!((stats.files, stats.deleted), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_delete_skipped_links() {
+        let data = tar_of(&[
+            TarEntry::from(("a.txt", "new")),
+            TarEntry::link("soft", async_tar::EntryType::Symlink, "a.txt"),
+            TarEntry::link("hard", async_tar::EntryType::Link, "a.txt"),
+        ])
+        .await;
+        let fake = FakeRemote::with_dirs(&["/", "/srv"]);
+        for (pth, target) in [
+            ("/srv/soft", "a.txt"),
+            ("/srv/hard", "a.txt"),
+            ("/srv/gone", "a.txt"),
+        ] {
+            let target = target.as_bytes().to_vec();
+            fake.put(pth, fake_stat(0o120777, target.len()), target);
+        }
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            symlinks: Symlinks::Skip,
+            delete: true,
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        // the archive has both links, so they stay even though neither was written
+        assert_eq!(
+            fake.paths(),
+            [
+                "/",
+                "/srv",
+                "/srv/.tmp",
+                "/srv/a.txt",
+                "/srv/hard",
+                "/srv/soft"
+            ]
+        );
+        assert_eq!((stats.files, stats.links, stats.deleted), (1, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let data = tar_of(&[
+            ("a.txt", "aaa"),
+            ("b.txt", "bbb"),
+            ("big.bin", "0123456789"),
+        ])
+        .await;
+        let fake = FakeRemote::with_dirs(&["/"]);
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            max_buffered: 4,
+            resume: true,
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        assert_eq!((stats.files, stats.skipped, stats.bytes), (3, 0, 16));
+
+        // interrupted before b.txt, and a.txt only compares by size
+        fake.unlink(&SimplePath::new("/srv/b.txt")).await.unwrap();
+        fake.put("/srv/a.txt", fake_stat(0o100644, 3), b"old".to_vec());
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        assert_eq!((stats.files, stats.skipped, stats.bytes), (1, 2, 3));
+        assert_eq!(fake.file("/srv/a.txt").as_deref(), Some("old"));
+        assert_eq!(fake.file("/srv/b.txt").as_deref(), Some("bbb"));
+
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        assert_eq!((stats.files, stats.skipped, stats.bytes), (0, 3, 0));
+    }
+
+    #[tokio::test]
+    async fn test_restore_batch() {
+        let files: Vec<_> = (0..24)
+            .map(|i| (format!("d{}/e{}/f.txt", i % 4, i), format!("file {}\n", i)))
+            .collect();
+        let entries: Vec<_> = files
+            .iter()
+            .map(|(n, d)| (n.as_str(), d.as_str()))
+            .collect();
+        let data = tar_of(&entries).await;
+        let fake = FakeRemote::with_dirs(&["/"]);
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        for (name, contents) in &files {
+            let pth = format!("/srv/{}", name);
+            assert_eq!(fake.file(&pth).as_ref(), Some(contents), "{}", pth);
+        }
+        assert_eq!(stats.files, 24);
+        // /srv, d0 to d3 and e0 to e23
+        assert_eq!(stats.created_dirs, 29);
+        let tmp = fake.readdir(&SimplePath::new("/srv/.tmp")).await.unwrap();
+        assert!(tmp.is_empty(), "{:?}", tmp);
+    }
+
+    #[tokio::test]
+    async fn test_clean_tmp() {
+        let data = tar_of(&[("data", "new")]).await;
+        for clean_tmp in [true, false] {
+            let fake = FakeRemote::with_dirs(&["/", "/srv", "/srv/.tmp", "/srv/.tmp/nested"]);
+            for pth in [
+                "/srv/.tmp/1234-0",
+                "/srv/.tmp/nested/1234-1",
+                "/srv/.tmpx",
+                "/srv/keep",
+            ] {
+                fake.put(pth, fake_stat(0o100644, 5), b"stale".to_vec());
+            }
+            let opts = RestoreOptions {
+                base_path: SimplePath::new("/srv"),
+                clean_tmp,
+                ..Default::default()
+            };
+            restore_all(&fake, [Archive::new(&data[..])], &opts)
+                .await
+                .unwrap();
+            assert_eq!(fake.file("/srv/.tmp/1234-0").is_none(), clean_tmp);
+            assert_eq!(fake.file("/srv/data").as_deref(), Some("new"));
+            // only files directly inside the temp directory go
+            for pth in ["/srv/.tmp/nested/1234-1", "/srv/.tmpx", "/srv/keep"] {
+                assert_eq!(fake.file(pth).as_deref(), Some("stale"), "{}", pth);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preserve_times() {
+        let data = tar_of(&[("big.bin", "0123456789"), ("small.txt", "ok")]).await;
+        for preserve_times in [true, false] {
+            let fake = FakeRemote::with_dirs(&["/"]);
+            let opts = RestoreOptions {
+                base_path: SimplePath::new("/srv"),
+                max_buffered: 4,
+                preserve_times,
+                ..Default::default()
+            };
+            restore_all(&fake, [Archive::new(&data[..])], &opts)
+                .await
+                .unwrap();
+            // streamed or not, both go through the same setstat
+            let times = preserve_times.then_some(TAR_MTIME);
+            for pth in ["/srv/big.bin", "/srv/small.txt"] {
+                let (stat, _) = fake.get(pth).unwrap();
+                assert_eq!((stat.atime, stat.mtime), (times, times), "{}", pth);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_streamed() {
+        let data = tar_of(&[("big.bin", "0123456789"), ("small.txt", "ok")]).await;
+        let fake = FakeRemote::with_dirs(&["/"]);
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            max_buffered: 4,
+            write_checksums: Some(SimplePath::new("SHA256SUMS")),
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        assert_eq!(fake.file("/srv/big.bin").as_deref(), Some("0123456789"));
+        assert_eq!(fake.file("/srv/small.txt").as_deref(), Some("ok"));
+        assert_eq!(stats.bytes, 12);
+        // hashed on its way to the server
+        let big = format!("{:x}  big.bin\n", Sha256::digest(b"0123456789"));
+        assert!(fake.file("/srv/SHA256SUMS").unwrap().contains(&big));
+
+        // skipped, it is read through just for the manifest
+        fake.unlink(&SimplePath::new("/srv/SHA256SUMS"))
+            .await
+            .unwrap();
+        let unchanged = RestoreOptions {
+            skip_unchanged: true,
+            ..opts
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &unchanged)
+            .await
+            .unwrap();
+        assert_eq!((stats.files, stats.skipped), (0, 2));
+        assert!(fake.file("/srv/SHA256SUMS").unwrap().contains(&big));
+    }
+
+    #[tokio::test]
+    async fn test_restore_same_path() {
+        // the older copy takes longer to write, so unless the two go in order it lands last
+        let data = tar_of(&[
+            ("etc/motd", "a much longer and older motd"),
+            ("etc/hosts", "127.0.0.1 localhost"),
+            ("etc/motd", "new"),
+        ])
+        .await;
+        let fake = FakeRemote::with_dirs(&["/"]);
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            ..Default::default()
+        };
+        let stats = restore_all(&fake, [Archive::new(&data[..])], &opts)
+            .await
+            .unwrap();
+        assert_eq!(fake.file("/srv/etc/motd").as_deref(), Some("new"));
+        let motd = stats
+            .uploaded
+            .iter()
+            .find(|f| f.path.as_str() == "/srv/etc/motd");
+        assert_eq!(motd.map(|f| f.size), Some(3));
+    }
+
+    #[test]
+    fn test_umask() {
+        let mut opts = RestoreOptions {
+            umask: 0o022,
+            ..Default::default()
+        };
+        assert_eq!(opts.file_mode(0o777), 0o644);
+        assert_eq!(opts.created_dir_mode(), 0o755);
+        assert_eq!(opts.recorded_dir_mode(0o2775), 0o2755);
+
+        opts.preserve_permissions = true;
+        opts.dir_mode = 0o777;
+        opts.umask = 0o027;
+        assert_eq!(opts.file_mode(0o4777), 0o4750);
+        assert_eq!(opts.file_mode(0o600), 0o600);
+        assert_eq!(opts.created_dir_mode(), 0o750);
+
+        opts.umask = 0;
+        assert_eq!(opts.file_mode(0o666), 0o666);
+        assert_eq!(opts.created_dir_mode(), 0o777);
+    }
+
+    #[test]
+    fn test_stat_matches() {
+        let stat = |perm, size, mtime| FileStat {
+            size: Some(size),
+            uid: None,
+            gid: None,
+            perm: Some(perm),
+            atime: None,
+            mtime: Some(mtime),
+        };
+        assert!(stat_matches(&stat(0o100644, 5, 1000), 5, 1000));
+        assert!(!stat_matches(&stat(0o100644, 6, 1000), 5, 1000));
+        assert!(!stat_matches(&stat(0o100644, 5, 1001), 5, 1000));
+        assert!(!stat_matches(&stat(0o040755, 5, 1000), 5, 1000));
+
+        assert!(is_complete(&stat(0o100644, 5, 1001), 5));
+        assert!(!is_complete(&stat(0o100644, 4, 1000), 5));
+        assert!(!is_complete(&stat(0o040755, 5, 1000), 5));
+    }
+
+    async fn read_entries(data: &[u8], opts: &RestoreOptions) -> Vec<(String, String)> {
+        Archive::new(data)
+            .entries()
+            .unwrap()
+            .map_err(RestoreError::Archive)
+            .try_filter_map(|mut ent| async move { read_entry(&mut ent, opts).await })
+            .try_filter(|entry| future::ready(!matches!(entry, Entry::Skipped { .. })))
+            .map_ok(|entry| match entry {
+                Entry::Dir { dst, .. } => (dst.to_string(), "dir".to_owned()),
+                Entry::File(upload) => (
+                    upload.dst.to_string(),
+                    String::from_utf8(upload.data.unwrap()).unwrap(),
+                ),
+                Entry::Symlink { dst, target } => (dst.to_string(), target.to_string()),
+                Entry::Skipped { .. } => unreachable!(),
+            })
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    async fn read_fixture(name: &str) -> Vec<(String, String)> {
+        let data = std::fs::read(Path::new("tests/fixtures").join(name)).unwrap();
+        read_entries(&data, &RestoreOptions::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_charset() {
+        let data = tar_of(&[TarEntry {
+            data: "hi",
+            ..TarEntry::raw(b"caf\xe9.txt", async_tar::EntryType::Regular)
+        }])
+        .await;
+
+        let entries = read_entries(&data, &RestoreOptions::default()).await;
+        assert_eq!(entries, [("caf\u{fffd}.txt".to_owned(), "hi".to_owned())]);
+
+        let opts = RestoreOptions {
+            charset: Encoding::for_label(b"latin1"),
+            ..Default::default()
+        };
+        let entries = read_entries(&data, &opts).await;
+        assert_eq!(entries, [("caf\u{e9}.txt".to_owned(), "hi".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_trailing_slash() {
+        // as in a v7 tar, which has no directory type, only the slash says srv/ is one
+        let data = tar_of(&[
+            TarEntry::raw(b"srv/", async_tar::EntryType::Regular),
+            TarEntry::dir("srv/www"),
+            TarEntry::raw(b"srv/motd", async_tar::EntryType::Regular),
+            TarEntry::link("srv/link/", async_tar::EntryType::Symlink, "www"),
+        ])
+        .await;
+
+        let entries = read_entries(&data, &RestoreOptions::default()).await;
+        assert_eq!(
+            entries,
+            [
+                ("srv".to_owned(), "dir".to_owned()),
+                ("srv/www".to_owned(), "dir".to_owned()),
+                ("srv/motd".to_owned(), "".to_owned()),
+                ("srv/link".to_owned(), "www".to_owned()),
+            ]
+        );
+        let dirs = collect_dirs(Archive::new(&data[..]), &RestoreOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(dirs, [SimplePath::new("srv"), SimplePath::new("srv/www")]);
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_absolute() {
+        // written raw, since set_path refuses absolute paths
+        let data = tar_of(&[
+            TarEntry {
+                data: "hi",
+                ..TarEntry::raw(b"/etc/motd", async_tar::EntryType::Regular)
+            },
+            TarEntry::from(("srv/motd", "ok")),
+        ])
+        .await;
+        let opts = |absolute_paths| RestoreOptions {
+            base_path: SimplePath::new("/srv/restore"),
+            absolute_paths,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            read_entries(&data, &opts(AbsolutePaths::Reroot)).await,
+            [
+                ("/srv/restore/etc/motd".to_owned(), "hi".to_owned()),
+                ("/srv/restore/srv/motd".to_owned(), "ok".to_owned())
+            ]
+        );
+        assert_eq!(
+            read_entries(&data, &opts(AbsolutePaths::Keep)).await,
+            [
+                ("/etc/motd".to_owned(), "hi".to_owned()),
+                ("/srv/restore/srv/motd".to_owned(), "ok".to_owned())
+            ]
+        );
+        let reject = opts(AbsolutePaths::Reject);
+        let rejected: Result<Vec<_>, _> = Archive::new(&data[..])
+            .entries()
+            .unwrap()
+            .map_err(RestoreError::Archive)
+            .try_filter_map(|mut ent| {
+                let reject = &reject;
+                async move { read_entry(&mut ent, reject).await }
+            })
+            .map_ok(|entry| entry.dst().to_string())
+            .try_collect()
+            .await;
+        assert!(matches!(
+            rejected,
+            Err(RestoreError::Path(PathError::Absolute(p))) if p == "/etc/motd"
+        ));
+
+        // stripping applies to what follows the root either way
+        let stripped = RestoreOptions {
+            strip_components: 1,
+            ..opts(AbsolutePaths::Keep)
+        };
+        assert_eq!(
+            read_entries(&data, &stripped).await,
+            [
+                ("/motd".to_owned(), "hi".to_owned()),
+                ("/srv/restore/motd".to_owned(), "ok".to_owned())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_max_size() {
+        let data = tar_of(&[("big.bin", "0123456789"), ("small.txt", "ok")]).await;
+        let opts = RestoreOptions {
+            max_file_size: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            read_entries(&data, &opts).await,
+            [("small.txt".to_owned(), "ok".to_owned())]
+        );
+
+        // a terabyte header with no data behind it fails before anything is read
+        let mut header = async_tar::Header::new_gnu();
+        header.set_path("huge.bin").unwrap();
+        header.set_size(1 << 40);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let opts = RestoreOptions {
+            max_file_size: Some(1 << 30),
+            oversized: Oversized::Fail,
+            ..Default::default()
+        };
+        let mut entries = Archive::new(&header.as_bytes()[..]).entries().unwrap();
+        let mut ent = entries.next().await.unwrap().unwrap();
+        match read_entry(&mut ent, &opts).await {
+            Err(RestoreError::Archive(e)) => {
+                assert_eq!(e.kind(), ErrorKind::InvalidData);
+                assert!(e.to_string().contains("huge.bin"), "{}", e);
+            }
+            _ => panic!("expected the entry to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_dirs() {
+        let data = tar_of(&[
+            TarEntry::link("e/f/link", async_tar::EntryType::Symlink, "../../d.txt"),
+            TarEntry::from(("a/b/c.txt", "")),
+            TarEntry::dir("a"),
+            TarEntry::from(("d.txt", "")),
+            TarEntry::from(("a/x.txt", "")),
+            TarEntry::from(("skip/y.txt", "")),
+        ])
+        .await;
+        let opts = RestoreOptions {
+            base_path: SimplePath::new("/srv"),
+            exclude: build_globset(&["skip/**".to_owned()]).unwrap(),
+            ..Default::default()
+        };
+        let dirs = collect_dirs(Archive::new(&data[..]), &opts).await.unwrap();
+        assert_eq!(
+            dirs.iter().map(SimplePath::as_str).collect::<Vec<_>>(),
+            ["/srv", "/srv/a", "/srv/e", "/srv/a/b", "/srv/e/f"]
+        );
+    }
+
+    /// The modification time of every entry in a [`tar_of`] archive.
+    const TAR_MTIME: u64 = 1_500_000_000;
+
+    /// One entry of a [`tar_of`] archive. A `(name, data)` pair is a regular file.
+    #[derive(Clone, Copy)]
+    struct TarEntry<'a> {
+        /// Copied into the header as is, so it can be any bytes
+        name: &'a [u8],
+        kind: async_tar::EntryType,
+        link: Option<&'a str>,
+        data: &'a str,
+    }
+
+    impl<'a> TarEntry<'a> {
+        fn raw(name: &'a [u8], kind: async_tar::EntryType) -> Self {
+            Self {
+                name,
+                kind,
+                link: None,
+                data: "",
+            }
+        }
+
+        fn dir(name: &'a str) -> Self {
+            Self::raw(name.as_bytes(), async_tar::EntryType::Directory)
+        }
+
+        /// A symlink or a hard link, by `kind`.
+        fn link(name: &'a str, kind: async_tar::EntryType, target: &'a str) -> Self {
+            Self {
+                link: Some(target),
+                ..Self::raw(name.as_bytes(), kind)
+            }
+        }
+    }
+
+    impl<'a> From<(&'a str, &'a str)> for TarEntry<'a> {
+        fn from((name, data): (&'a str, &'a str)) -> Self {
+            Self {
+                data,
+                ..Self::raw(name.as_bytes(), async_tar::EntryType::Regular)
+            }
+        }
+    }
+
+    async fn tar_of<'a, E: Into<TarEntry<'a>> + Copy>(entries: &[E]) -> Vec<u8> {
+        let mut builder = async_tar::Builder::new(Vec::new());
+        for &entry in entries {
+            let entry = entry.into();
+            let mut header = async_tar::Header::new_gnu();
+            header.as_old_mut().name[..entry.name.len()].copy_from_slice(entry.name);
+            header.set_entry_type(entry.kind);
+            if let Some(link) = entry.link {
+                header.set_link_name(link).unwrap();
+            }
+            header.set_size(entry.data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(TAR_MTIME);
+            header.set_cksum();
+            builder
+                .append(&header, entry.data.as_bytes())
+                .await
+                .unwrap();
+        }
+        builder.into_inner().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tally() {
+        let data = tar_of(&[
+            TarEntry::dir("etc"),
+            TarEntry::from(("etc/motd", "hello\n")),
+            TarEntry::from(("etc/hosts", "127.0.0.1 localhost\n")),
+            TarEntry::link("etc/issue", async_tar::EntryType::Symlink, "motd"),
+            TarEntry::from(("etc/motd", "hi\n")),
+        ])
+        .await;
+        let (tally, opts) = (Tally::default(), RestoreOptions::default());
+        // counted the way the upload pipeline does
+        Archive::new(&data[..])
+            .entries()
+            .unwrap()
+            .map_err(RestoreError::Archive)
+            .try_filter_map(|mut ent| {
+                let opts = &opts;
+                async move { read_entry(&mut ent, opts).await }
+            })
+            .try_for_each(|entry| {
+                match entry {
+                    Entry::Dir { .. } => tally.dir(),
+                    Entry::File(upload) => tally.file(&upload),
+                    Entry::Symlink { .. } => tally.link(),
+                    Entry::Skipped { .. } => {}
+                }
+                future::ok(())
+            })
+            .await
+            .unwrap();
+        tally.skip();
+
+        let stats = tally.into_stats(2, 0, Duration::from_secs(2));
+        assert_eq!(stats.dirs, 1);
+        assert_eq!(stats.created_dirs, 2);
+        // a path written twice is one file, but both uploads were sent
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.links, 1);
+        assert_eq!(stats.bytes, 29);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.throughput(), 14.5);
+        let uploaded: Vec<_> = stats
+            .uploaded
+            .iter()
+            .map(|file| (file.path.as_str(), file.size))
+            .collect();
+        assert_eq!(uploaded, [("etc/hosts", 20), ("etc/motd", 3)]);
+        assert_eq!(stats.uploaded[1].mtime, TAR_MTIME);
+        assert_eq!(RestoreStats::default().throughput(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_on_error() {
+        let data = tar_of(&[("a", "1"), ("bad", "22"), ("c", "333")]).await;
+        // the server rejects one file
+        let restore = |on_error| {
+            let data = data.clone();
+            async move {
+                let mut fake = FakeRemote::with_dirs(&["/"]);
+                fake.refused.insert(SimplePath::new("/srv/bad"));
+                let opts = RestoreOptions {
+                    base_path: SimplePath::new("/srv"),
+                    on_error,
+                    ..Default::default()
+                };
+                let result = restore_all(&fake, [Archive::new(&data[..])], &opts).await;
+                (fake, result)
+            }
+        };
+
+        let (fake, stats) = restore(OnError::Continue).await;
+        let stats = stats.unwrap();
+        assert_eq!(fake.file("/srv/a").as_deref(), Some("1"));
+        assert_eq!(fake.file("/srv/c").as_deref(), Some("333"));
+        assert_eq!(fake.file("/srv/bad"), None);
+        assert_eq!((stats.files, stats.bytes), (2, 4));
+        assert_eq!(stats.failed.len(), 1);
+        assert_eq!(stats.failed[0].path.as_str(), "/srv/bad");
+        assert!(
+            stats.failed[0].error.contains("permission denied"),
+            "{}",
+            stats.failed[0].error
+        );
+        assert!(matches!(
+            stats.check_complete(),
+            Err(RestoreError::Incomplete { failed: 1 })
+        ));
+
+        let (fake, result) = restore(OnError::Abort).await;
+        match result {
+            Err(RestoreError::Sftp { source, .. }) => {
+                assert_eq!(source.kind(), ErrorKind::PermissionDenied)
+            }
+            _ => panic!("expected the restore to stop at the rejected file"),
+        }
+        assert_eq!(fake.file("/srv/bad"), None);
+
+        // nothing can be restored past a broken archive
+        let tally = Tally::default();
+        let broken = RestoreError::Archive(Error::other("corrupt"));
+        assert!(tally
+            .settle(&SimplePath::new("a"), Err(broken), OnError::Continue)
+            .is_err());
+        assert!(tally.failed.lock().unwrap().is_empty());
+        assert_eq!(
+            RestoreError::Incomplete { failed: 3 }.to_string(),
+            "3 entries could not be restored"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_latest() {
+        // what two archives restored in order leave behind
+        let first = tar_of(&[("etc/motd", "old"), ("etc/hosts", "hosts")]).await;
+        let second = tar_of(&[("var/log", "log"), ("etc/motd", "new")]).await;
+        let opts = RestoreOptions::default();
+        let mut written = read_entries(&first, &opts).await;
+        written.extend(read_entries(&second, &opts).await);
+        let tree = keep_latest(written, |(pth, _)| pth);
+        assert_eq!(
+            tree,
+            [
+                ("etc/hosts".to_owned(), "hosts".to_owned()),
+                ("etc/motd".to_owned(), "new".to_owned()),
+                ("var/log".to_owned(), "log".to_owned())
+            ]
+        );
+        assert_eq!(
+            keep_latest(vec![(2, 'a'), (1, 'b'), (2, 'c')], |(k, _)| k),
+            [(1, 'b'), (2, 'c')]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_owner() {
+        let mut header = async_tar::Header::new_gnu();
+        header.set_path("f.txt").unwrap();
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_cksum();
+        let mut builder = async_tar::Builder::new(Vec::new());
+        builder.append(&header, &b""[..]).await.unwrap();
+        let data = builder.into_inner().await.unwrap();
+        let owner = |opts: RestoreOptions| {
+            let data = data.clone();
+            async move {
+                let mut entries = Archive::new(&data[..]).entries().unwrap();
+                let mut ent = entries.next().await.unwrap().unwrap();
+                match read_entry(&mut ent, &opts).await.unwrap() {
+                    Some(Entry::File(upload)) => upload.owner,
+                    _ => panic!("expected a file"),
+                }
+            }
+        };
+
+        assert_eq!(owner(RestoreOptions::default()).await, Owner::default());
+        let preserved = RestoreOptions {
+            preserve_owner: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            owner(preserved.clone()).await,
+            Owner {
+                uid: Some(1000),
+                gid: Some(100)
+            }
+        );
+        // --chown wins for the ids it sets and leaves the rest to the archive
+        let chown = Owner {
+            uid: None,
+            gid: Some(0),
+        };
+        assert_eq!(
+            owner(RestoreOptions {
+                chown: Some(chown),
+                ..preserved
+            })
+            .await,
+            Owner {
+                uid: Some(1000),
+                gid: Some(0)
+            }
+        );
+        assert_eq!(
+            owner(RestoreOptions {
+                chown: Some(chown),
+                ..Default::default()
+            })
+            .await,
+            chown
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_long_names() {
+        let dir = vec!["d".repeat(60); 4].join("/");
+        let file = format!("{}/{}.txt", dir, "f".repeat(50));
+        assert!(file.len() > 255);
+        for fixture in ["long_names_gnu.tar", "long_names_pax.tar"] {
+            let entries = read_fixture(fixture).await;
+            assert_eq!(entries.len(), 6, "{}", fixture);
+            assert_eq!(entries[3], (dir.clone(), "dir".to_owned()), "{}", fixture);
+            assert_eq!(
+                entries[4],
+                (file.clone(), "hello\n".to_owned()),
+                "{}",
+                fixture
+            );
+            assert_eq!(entries[5], ("link".to_owned(), file.clone()), "{}", fixture);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_limited() {
+        let data = vec![7; 20_000];
+        let mut out = Vec::new();
+        let limiter = RateLimiter::new(100_000);
+        let started = Instant::now();
+        let written = copy_chunked(&data[..], &mut out, 4096, Some(&limiter))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(190));
+        assert_eq!(written, 20_000);
+        assert_eq!(out, data);
+    }
+
+    #[tokio::test]
+    async fn test_copy_chunked() {
+        struct Writes(Vec<usize>);
+        impl AsyncWrite for Writes {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize, Error>> {
+                self.0.push(buf.len());
+                Poll::Ready(Ok(buf.len()))
+            }
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+                Poll::Ready(Ok(()))
+            }
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+        let mut writes = Writes(Vec::new());
+        let written = copy_chunked(&[0; 600 * 1024][..], &mut writes, 256 * 1024, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(written, 600 * 1024);
+        assert_eq!(writes.0, [256 * 1024, 256 * 1024, 88 * 1024]);
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let res = with_timeout(Some(0), "waiting", future::pending::<Result<(), _>>()).await;
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+        let res = with_timeout(Some(1), "waiting", future::ready(Ok(1))).await;
+        assert_eq!(res.unwrap(), 1);
+        let res = with_timeout(None, "waiting", future::ready(Ok(1))).await;
+        assert_eq!(res.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remote_target() {
+        // a server that hangs up before saying anything
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || drop(listener.accept().unwrap()));
+        let stream = async_io::Async::<std::net::TcpStream>::connect(addr)
+            .await
+            .unwrap();
+        server.join().unwrap();
+        let session = Arc::new(AsyncSession::new(stream, None).unwrap());
+
+        let opened =
+            tokio::time::timeout(Duration::from_secs(5), RemoteTarget::new(session.clone()))
+                .await
+                .expect("opening sftp on a dead session hangs");
+        assert!(matches!(opened, Err(RestoreError::Connect(_))));
+        // the session and libssh2 both own the socket and would each close it
+        std::mem::forget(session);
+    }
+
+    #[tokio::test]
+    async fn test_with_hooks() {
+        let opts = RestoreOptions {
+            pre_command: Some("systemctl stop app".to_owned()),
+            post_command: Some("systemctl start app".to_owned()),
+            ..Default::default()
+        };
+        let ran = Mutex::new(Vec::new());
+        // runs every command, failing the ones containing `fails`
+        let hook = |fails: &'static str| {
+            let ran = &ran;
+            move |command: String| {
+                ran.lock().unwrap().push(command.clone());
+                let result = match command.contains(fails) {
+                    true => Err(RestoreError::Hook {
+                        command,
+                        source: Error::other("exited with status 1"),
+                    }),
+                    false => Ok(()),
+                };
+                async { result }
+            }
+        };
+        let restored = AtomicUsize::new(0);
+        let restore = |result: Result<(), RestoreError>| async {
+            restored.fetch_add(1, Ordering::Relaxed);
+            result
+        };
+
+        // a failed pre-command aborts before anything is restored
+        let result = with_hooks(&opts, hook("stop"), restore(Ok(()))).await;
+        assert!(
+            matches!(result, Err(RestoreError::Hook { command, .. }) if command == "systemctl stop app")
+        );
+        assert_eq!(restored.load(Ordering::Relaxed), 0);
+        assert_eq!(*ran.lock().unwrap(), ["systemctl stop app"]);
+
+        ran.lock().unwrap().clear();
+        let result = with_hooks(&opts, hook("start"), restore(Ok(()))).await;
+        assert!(matches!(result, Err(RestoreError::Hook { .. })));
+        assert_eq!(restored.load(Ordering::Relaxed), 1);
+        assert_eq!(ran.lock().unwrap().len(), 2);
+
+        // the post-command only follows a failed restore with post_always
+        ran.lock().unwrap().clear();
+        let failed = || restore(Err(RestoreError::Interrupted));
+        let result = with_hooks(&opts, hook("-"), failed()).await;
+        assert!(matches!(result, Err(RestoreError::Interrupted)));
+        assert_eq!(*ran.lock().unwrap(), ["systemctl stop app"]);
+
+        ran.lock().unwrap().clear();
+        let always = RestoreOptions {
+            post_always: true,
+            ..opts
+        };
+        let result = with_hooks(&always, hook("start"), failed()).await;
+        assert!(matches!(result, Err(RestoreError::Interrupted)));
+        assert_eq!(
+            *ran.lock().unwrap(),
+            ["systemctl stop app", "systemctl start app"]
+        );
+
+        ran.lock().unwrap().clear();
+        let dry_run = RestoreOptions {
+            dry_run: true,
+            ..always
+        };
+        let result = with_hooks(&dry_run, hook("stop"), restore(Ok(()))).await;
+        assert!(result.is_ok());
+        assert!(ran.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_with_file_timeout() {
+        /// Takes a second over every write, like a stalled channel.
+        struct SlowWriter;
+        impl AsyncWrite for SlowWriter {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize, Error>> {
+                let mut sleep = Box::pin(tokio::time::sleep(Duration::from_secs(1)));
+                sleep.as_mut().poll(cx).map(|()| Ok(buf.len()))
+            }
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+                Poll::Ready(Ok(()))
+            }
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+        let path = SimplePath::new("/srv/slow.bin");
+        let mut writer = SlowWriter;
+        let copy = copy_chunked(&[0; 1024][..], &mut writer, 256, None);
+        let err = with_file_timeout(Some(0), &path, copy.map_err(RestoreError::sftp(&path)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "/srv/slow.bin: upload timed out after 0s");
+        assert!(err.is_transient());
+
+        let fast = with_file_timeout(Some(5), &path, future::ready(Ok(1))).await;
+        assert_eq!(fast.unwrap(), 1);
+        let fast = with_file_timeout(None, &path, future::ready(Ok(1))).await;
+        assert_eq!(fast.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let path = SimplePath::new("a");
+        let sftp_err = RestoreError::sftp(&path);
+
+        let attempts = AtomicUsize::new(0);
+        let res = retry(3, Duration::from_millis(1), || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(sftp_err(Error::from(ErrorKind::ConnectionReset))),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 1);
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), _> = retry(3, Duration::from_millis(1), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(RestoreError::Transfer {
+                path: path.clone(),
+                expected: 10,
+                wrote: 5,
+            })
+        })
+        .await;
+        assert!(matches!(res, Err(RestoreError::Transfer { wrote: 5, .. })));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), _> = retry(2, Duration::from_millis(1), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(sftp_err(Error::from(ErrorKind::TimedOut)))
+        })
+        .await;
+        assert!(res.unwrap_err().is_transient());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_restore_error() {
+        let err = RestoreError::sftp(&SimplePath::new("/srv/a"))(Error::from(ErrorKind::NotFound));
+        assert_eq!(err.to_string(), "/srv/a: entity not found");
+        assert!(std::error::Error::source(&err).is_some());
+        assert!(!err.is_transient());
+
+        let err = RestoreError::from(PathError::Traversal("../x".to_owned()));
+        assert_eq!(err.to_string(), "path escapes its root: ../x");
+    }
+
+    #[test]
+    fn test_out_of_space() {
+        let path = SimplePath::new("/srv/a");
+        // the message libssh2 gives LIBSSH2_FX_NO_SPACE_ON_FILESYSTEM
+        for source in [
+            Error::other("no space on filesystem"),
+            Error::other("quota exceeded"),
+            Error::other("scp: /srv/.tmp/a: No space left on device"),
+            Error::from(ErrorKind::StorageFull),
+        ] {
+            let err = RestoreError::sftp(&path)(source);
+            assert!(matches!(err, RestoreError::OutOfSpace { .. }), "{:?}", err);
+            assert_eq!(
+                err.to_string(),
+                "/srv/a: the server is out of disk space or quota"
+            );
+            assert!(!err.is_transient());
+        }
+        let err = RestoreError::sftp(&path)(Error::other("failure"));
+        assert!(matches!(err, RestoreError::Sftp { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_hash_reader() {
+        let mut reader = HashReader::new(&b"hello\n"[..]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello\n");
+        assert_eq!(
+            reader.hex_digest(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+    }
+
+    #[test]
+    fn test_checksum_line() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            checksum_line(&SimplePath::new("etc/motd"), sha256),
+            format!("{}  etc/motd\n", sha256)
+        );
+        assert_eq!(
+            checksum_line(&SimplePath::new("two\nlines"), sha256),
+            format!("\\{}  two\\nlines\n", sha256)
+        );
+        let manifest = checksum_manifest(vec![
+            (SimplePath::new("b/z"), "2".repeat(64)),
+            (SimplePath::new("a"), "1".repeat(64)),
+            (SimplePath::new("b/a"), "3".repeat(64)),
+        ]);
+        assert_eq!(
+            manifest,
+            format!(
+                "{}  a\n{}  b/a\n{}  b/z\n",
+                "1".repeat(64),
+                "3".repeat(64),
+                "2".repeat(64)
+            )
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/srv/a b"), "'/srv/a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_symlinks() {
+        let data = tar_of(&[
+            TarEntry::from(("a.txt", "")),
+            TarEntry::link("soft", async_tar::EntryType::Symlink, "a.txt"),
+            TarEntry::link("hard", async_tar::EntryType::Link, "a.txt"),
+        ])
+        .await;
+        let opts = |symlinks| RestoreOptions {
+            symlinks,
+            ..Default::default()
+        };
+        let linked = [
+            ("a.txt".to_owned(), "".to_owned()),
+            ("soft".to_owned(), "a.txt".to_owned()),
+            ("hard".to_owned(), "a.txt".to_owned()),
+        ];
+
+        assert_eq!(read_entries(&data, &opts(Symlinks::Recreate)).await, linked);
+        assert_eq!(read_entries(&data, &opts(Symlinks::Follow)).await, linked);
+        assert_eq!(
+            read_entries(&data, &opts(Symlinks::Skip)).await,
+            [("a.txt".to_owned(), "".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_follow_link() {
+        let p = |s: &str| SimplePath::new(s);
+        let files = HashMap::from([(p("/srv/a.txt"), 1)]);
+        let links = BTreeMap::from([
+            (p("/srv/direct"), p("/srv/a.txt")),
+            (p("/srv/chained"), p("/srv/direct")),
+            (p("/srv/dangling"), p("/srv/missing")),
+            (p("/srv/dir"), p("/srv")),
+            (p("/srv/loop"), p("/srv/loop")),
+        ]);
+        let follow =
+            |dst: &str| follow_link(&p(dst), &links, &files).map(|(pth, &v)| (pth.as_str(), v));
+
+        assert_eq!(follow("/srv/direct"), Some(("/srv/a.txt", 1)));
+        assert_eq!(follow("/srv/chained"), Some(("/srv/a.txt", 1)));
+        assert_eq!(follow("/srv/dangling"), None);
+        assert_eq!(follow("/srv/dir"), None);
+        assert_eq!(follow("/srv/loop"), None);
+        assert_eq!(follow("/srv/a.txt"), None);
+    }
+
+    #[test]
+    fn test_link_target() {
+        let base = SimplePath::new("/srv/restore");
+        let dst = SimplePath::new("/srv/restore/usr/bin/python");
+        let target = |t: &str, hard| link_target(&base, &dst, t, hard).map(|p| p.to_string());
+
+        assert_eq!(target("python3", false).as_deref(), Ok("python3"));
+        assert_eq!(target("../lib/x", false).as_deref(), Ok("../lib/x"));
+        assert_eq!(target("/usr/bin/python3", false).as_deref(), Ok("python3"));
+        assert_eq!(
+            target("/etc/passwd", false).as_deref(),
+            Ok("../../etc/passwd")
+        );
+        assert_eq!(target("usr/lib/x", true).as_deref(), Ok("../lib/x"));
+        assert!(target("../../../etc/passwd", false).is_err());
+        assert!(target("../../etc", true).is_err());
+
+        let base = SimplePath::new(".");
+        let dst = SimplePath::new("a/link");
+        assert_eq!(
+            link_target(&base, &dst, "../b", false).map(|p| p.to_string()),
+            Ok("../b".to_owned())
+        );
+        assert!(link_target(&base, &dst, "../../b", false).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The settings from an ssh config that apply to one host.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+}
+
+/// Reads the ssh config at `path`, or nothing if it doesn't exist.
+pub fn load(path: &Path, host: &str) -> Result<HostConfig, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(config) => parse(&config, host, home_dir().as_deref())
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HostConfig::default()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".ssh").join("config"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Collects the settings for `host` from every matching `Host` block. Like
+/// ssh, the first value found for a keyword wins, except that every
+/// `IdentityFile` is kept, and `Match` blocks never apply.
+pub fn parse(config: &str, host: &str, home: Option<&Path>) -> Result<HostConfig, Error> {
+    let mut found = HostConfig::default();
+    let mut active = true;
+    for (n, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // keywords and values are split by whitespace, an `=`, or both
+        let is_sep = |c: char| c.is_whitespace() || c == '=';
+        let (keyword, value) = line.split_at(line.find(is_sep).unwrap_or(line.len()));
+        let value = value.trim_start_matches(is_sep).trim_matches('"');
+        let invalid = |what: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: {} {}", n + 1, what, value),
+            )
+        };
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => active = host_matches(value, host),
+            "match" => active = false,
+            _ if !active => {}
+            "hostname" if found.host_name.is_none() => {
+                found.host_name = Some(value.replace("%h", host).replace("%%", "%"))
+            }
+            "user" if found.user.is_none() => found.user = Some(value.to_owned()),
+            "port" if found.port.is_none() => {
+                found.port = Some(value.parse().map_err(|_| invalid("invalid port"))?)
+            }
+            "identityfile" => found
+                .identity_files
+                .push(match (value.strip_prefix("~/"), home) {
+                    (Some(rest), Some(home)) => home.join(rest),
+                    _ => PathBuf::from(value),
+                }),
+            "proxyjump" if found.proxy_jump.is_none() && value != "none" => {
+                found.proxy_jump = Some(value.to_owned())
+            }
+            _ => {}
+        }
+    }
+    Ok(found)
+}
+
+/// Whether `host` matches a `Host` line, where any `!pattern` vetoes the match.
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard(negated.as_bytes(), host.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= wildcard(pattern.as_bytes(), host.as_bytes()),
+        }
+    }
+    matched
+}
+
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, _) => text.is_empty(),
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, text) || (!text.is_empty() && wildcard(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => wildcard(rest, text),
+        (Some((p, rest)), Some((t, text))) if p == t => wildcard(rest, text),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/ssh_config");
+
+    fn lookup(host: &str) -> HostConfig {
+        parse(FIXTURE, host, Some(Path::new("/home/me"))).unwrap()
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        assert_eq!(
+            lookup("backup-server"),
+            HostConfig {
+                host_name: Some("backup.example.com".to_owned()),
+                user: Some("restore".to_owned()),
+                port: Some(2222),
+                identity_files: vec![
+                    "/home/me/.ssh/backup_ed25519".into(),
+                    "/home/me/.ssh/id_ed25519".into()
+                ],
+                proxy_jump: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        let web = lookup("web1.internal");
+        assert_eq!(web.proxy_jump.as_deref(), Some("bastion.internal"));
+        assert_eq!(web.user.as_deref(), Some("ops"));
+        assert_eq!(web.port, Some(22));
+
+        let bastion = lookup("bastion.internal");
+        assert_eq!(bastion.proxy_jump, None);
+        assert_eq!(bastion.user.as_deref(), Some("fallback"));
+        assert_eq!(bastion.port, Some(2200));
+
+        assert_eq!(lookup("db1").host_name.as_deref(), Some("db1.example.com"));
+        assert_eq!(
+            lookup("DB-main").host_name.as_deref(),
+            Some("DB-main.example.com")
+        );
+        assert_eq!(lookup("db12").host_name, None);
+    }
+
+    #[test]
+    fn test_parse_fallback() {
+        let other = lookup("other.example.com");
+        assert_eq!(other.host_name, None);
+        assert_eq!(other.user.as_deref(), Some("fallback"));
+        assert_eq!(
+            other.identity_files,
+            [PathBuf::from("/home/me/.ssh/id_ed25519")]
+        );
+        assert_eq!(parse("", "host", None).unwrap(), HostConfig::default());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let err = parse("Host *\n  Port ssh\n", "host", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"));
+        // settings for other hosts are never looked at
+        assert!(parse("Host other\n  Port ssh\n", "host", None).is_ok());
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert!(wildcard(b"*", b""));
+        assert!(wildcard(b"*.example.com", b"a.b.example.com"));
+        assert!(wildcard(b"h?st", b"host"));
+        assert!(!wildcard(b"h?st", b"hst"));
+        assert!(!wildcard(b"host", b"hostname"));
+    }
+}
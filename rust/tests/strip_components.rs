@@ -0,0 +1,46 @@
+use async_tar::{Archive, Builder, Header};
+use futures::prelude::*;
+
+use bakelite_ssh_backend::SimplePath;
+
+async fn build_tar(paths: &[&str]) -> Vec<u8> {
+    let mut builder = Builder::new(Vec::new());
+    for path in paths {
+        let data = path.as_bytes();
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).await.unwrap();
+    }
+    builder.into_inner().await.unwrap()
+}
+
+async fn stripped_paths(data: &[u8], n: usize) -> Vec<String> {
+    Archive::new(data)
+        .entries()
+        .unwrap()
+        .map_ok(|ent| {
+            SimplePath::new(ent.path().unwrap().to_string_lossy())
+                .strip_components(n)
+                .map(|p| p.to_string())
+        })
+        .try_filter_map(future::ok)
+        .try_collect()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_strip_components_in_memory_tar() {
+    let data = build_tar(&["wrap/a", "wrap/dir/b", "top"]).await;
+
+    let paths = stripped_paths(&data, 0).await;
+    assert_eq!(paths, ["wrap/a", "wrap/dir/b", "top"]);
+
+    let paths = stripped_paths(&data, 1).await;
+    assert_eq!(paths, ["a", "dir/b"]);
+
+    let paths = stripped_paths(&data, 2).await;
+    assert_eq!(paths, ["b"]);
+}